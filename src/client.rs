@@ -1,9 +1,27 @@
-use crate::packet::{self, ToClient, ToRobot};
-use std::net::{AddrParseError, TcpStream, ToSocketAddrs};
+use crate::{
+    packet::{self, Capabilities, FrameDecoder, Heartbeat, PongSource, ToClient, ToRobot},
+    rpc::{self, Rpc},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{AddrParseError, SocketAddr, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
 
 // NOTE: Client will be in a codebase that doesn't use the same logging framework
 // that is in lib.rs so log::info will not be routed through Mediator
 
+// upper bound on a single connection attempt made by ReconnectingClient::poll
+// so that a GUI calling it every frame isn't stalled for long
+const RECONNECT_ATTEMPT_TIMEOUT: Duration = Duration::from_millis(100);
+// how often Client::call checks for the response
+const CALL_POLL_INTERVAL: Duration = Duration::from_millis(1);
+// number of round trips RttStats are calculated over
+const RTT_WINDOW: usize = 64;
+// older pings are forgotten so a robot that never answers can't grow the
+// list forever
+const MAX_OUTSTANDING_PINGS: usize = 64;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("IO error:\n{0}")]
@@ -12,55 +30,789 @@ pub enum Error {
     Packet(#[from] packet::Error),
     #[error("invalid ip:\n{0}")]
     AddrParse(#[from] AddrParseError),
+    #[error("address did not resolve to any socket addresses")]
+    NoAddress,
+    #[error("timed out connecting to robot")]
+    Timeout,
+    #[error("not connected to robot")]
+    NotConnected,
+}
+
+// delay between connection attempts, grows by multiplier after every failed
+// attempt until it reaches max
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(50),
+            max: Duration::from_secs(2),
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl Backoff {
+    // delay before the attempt after `failed_attempts` failures
+    pub fn delay(&self, failed_attempts: u32) -> Duration {
+        let factor = self
+            .multiplier
+            .powi(failed_attempts.min(i32::MAX as u32) as i32);
+        // done in f64 since the factor can overflow a Duration. A negative
+        // multiplier is treated as no delay rather than panicking
+        let secs = (self.initial.as_secs_f64() * factor)
+            .min(self.max.as_secs_f64())
+            .max(0.0);
+        Duration::from_secs_f64(secs)
+    }
 }
+
 pub struct Client {
     stream: TcpStream,
+    decoder: FrameDecoder,
+    capabilities: Capabilities,
+    // an error hit while reading packets that were still returned, it is
+    // returned by the next call to receive_data instead
+    pending_error: Option<packet::Error>,
+    next_call_id: u64,
+    // calls made with call_with that are waiting on a response
+    pending_calls: HashMap<u64, PendingCall>,
+    // packets received while blocked in call, returned by the next
+    // receive_data
+    queued: Vec<ToClient>,
+    next_ping: u64,
+    sent_pings: VecDeque<SentPing>,
+    listener_rtt: RttWindow,
+    mediator_rtt: RttWindow,
+    heartbeat: Heartbeat,
+    last_received: Instant,
+    last_sent: Instant,
+    state: ConnectionState,
+}
+
+struct SentPing {
+    seq: u64,
+    sent: Instant,
+    listener_answered: bool,
+    mediator_answered: bool,
+}
+
+impl SentPing {
+    fn answered(&self, source: PongSource) -> bool {
+        match source {
+            PongSource::Listener => self.listener_answered,
+            PongSource::Mediator => self.mediator_answered,
+        }
+    }
+    fn answered_mut(&mut self, source: PongSource) -> &mut bool {
+        match source {
+            PongSource::Listener => &mut self.listener_answered,
+            PongSource::Mediator => &mut self.mediator_answered,
+        }
+    }
+}
+
+// round trip times of the last RTT_WINDOW pongs from one PongSource
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RttStats {
+    pub last: Duration,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    // mean difference between consecutive round trips
+    pub jitter: Duration,
+    pub samples: usize,
+}
+
+#[derive(Default)]
+struct RttWindow(VecDeque<Duration>);
+
+impl RttWindow {
+    fn push(&mut self, rtt: Duration) {
+        if self.0.len() == RTT_WINDOW {
+            self.0.pop_front();
+        }
+        self.0.push_back(rtt);
+    }
+    fn stats(&self) -> Option<RttStats> {
+        let last = *self.0.back()?;
+        let samples = self.0.len();
+        let total: Duration = self.0.iter().sum();
+        let jitter: Duration = self
+            .0
+            .iter()
+            .zip(self.0.iter().skip(1))
+            .map(|(a, b)| a.abs_diff(*b))
+            .sum();
+        Some(RttStats {
+            last,
+            min: *self.0.iter().min()?,
+            avg: total / samples as u32,
+            max: *self.0.iter().max()?,
+            jitter: jitter / (samples - 1).max(1) as u32,
+            samples,
+        })
+    }
+}
+
+// called with None if the call timed out
+type Callback = Box<dyn FnOnce(Option<Result<Vec<u8>, String>>) + Send>;
+
+struct PendingCall {
+    deadline: Instant,
+    callback: Callback,
 }
 
 impl Client {
+    // blocks until connected, retrying with the default Backoff while the
+    // robot refuses connections
     pub fn new<A: ToSocketAddrs + Clone>(addr: A) -> Result<Self, Error> {
-        let stream;
-        loop {
+        let backoff = Backoff::default();
+        let mut failed_attempts = 0;
+        let stream = loop {
             match TcpStream::connect(addr.clone()) {
-                Ok(s) => stream = s,
-                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => continue,
+                Ok(s) => break s,
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    std::thread::sleep(backoff.delay(failed_attempts));
+                    failed_attempts += 1;
+                }
                 Err(e) => return Err(e)?,
             }
-            break;
+        };
+        Self::from_stream(stream, packet::HANDSHAKE_TIMEOUT)
+    }
+
+    // like Client::new but gives up with Error::Timeout after timeout,
+    // including while waiting on the robot's side of the handshake
+    pub fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<Self, Error> {
+        Self::connect_with_backoff(&resolve(addr)?, timeout, Backoff::default())
+    }
+
+    pub(crate) fn connect_with_backoff(
+        addrs: &[SocketAddr],
+        timeout: Duration,
+        backoff: Backoff,
+    ) -> Result<Self, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut failed_attempts = 0;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(Error::Timeout);
+            }
+            match connect_any(addrs, remaining) {
+                Ok(stream) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(Error::Timeout);
+                    }
+                    return Self::from_stream(stream, remaining).map_err(|e| {
+                        match Instant::now() >= deadline {
+                            true => Error::Timeout,
+                            false => e,
+                        }
+                    });
+                }
+                Err(e) if is_retryable(&e) => {}
+                Err(e) => return Err(e)?,
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            std::thread::sleep(backoff.delay(failed_attempts).min(remaining));
+            failed_attempts += 1;
         }
-        stream.set_nonblocking(true)?;
-        let mut a = Self { stream };
+    }
+
+    // gives up on the handshake after timeout
+    fn from_stream(mut stream: TcpStream, timeout: Duration) -> Result<Self, Error> {
+        // requests are small and sent as they happen, Nagle's algorithm
+        // would hold them back (and skew ping times) while an earlier one
+        // is unacknowledged
+        stream.set_nodelay(true)?;
+        let capabilities = packet::handshake_timeout(&mut stream, Capabilities::ALL, timeout)?;
+        let mut a = Self {
+            stream,
+            decoder: FrameDecoder::default(),
+            capabilities,
+            pending_error: None,
+            next_call_id: 0,
+            pending_calls: HashMap::new(),
+            queued: Vec::new(),
+            next_ping: 0,
+            sent_pings: VecDeque::new(),
+            listener_rtt: RttWindow::default(),
+            mediator_rtt: RttWindow::default(),
+            heartbeat: Heartbeat::default(),
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            state: ConnectionState::Connected,
+        };
         a.send_request(&ToRobot::RequestLogs)?;
         Ok(a)
     }
 
+    // packets from the robot larger than this are treated as an error,
+    // defaults to packet::DEFAULT_MAX_FRAME_LEN
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.decoder.set_max_frame_len(max_frame_len);
+    }
+
+    // the robot's heartbeat interval (see LoggerConfig::heartbeat) should
+    // be shorter than this timeout. receive_data has to be called more
+    // often than the robot's timeout for this client's heartbeats to be sent
+    pub fn set_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.heartbeat = heartbeat;
+    }
+
+    // Disconnected once receive_data or a send has failed, including the
+    // robot timing out, after which the client can't be used again
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    // how long since anything (including a heartbeat) was last received as
+    // of the last receive_data
+    pub fn silent_for(&self) -> Duration {
+        self.last_received.elapsed()
+    }
+
+    // capabilities supported by both the client and the robot
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    // also runs the callbacks of call_with whose response arrived or that
+    // timed out, responses are never returned here
     pub fn receive_data(&mut self) -> Result<Vec<ToClient>, packet::Error> {
+        let mut pkts = std::mem::take(&mut self.queued);
+        match self.read_packets() {
+            Ok(received) => pkts.extend(received),
+            Err(e) if !pkts.is_empty() => self.pending_error = Some(e),
+            Err(e) => return Err(e),
+        }
+        let pkts = pkts
+            .into_iter()
+            .filter_map(|pkt| self.answer_call(pkt))
+            .collect();
+        self.expire_calls();
+        Ok(pkts)
+    }
+
+    pub fn send_request(&mut self, pkt: &ToRobot) -> Result<(), packet::Error> {
+        packet::send(&mut self.stream, &pkt).map_err(|e| self.disconnected(e))?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    // sends a ToRobot::Ping, its round trip times show up in rtt once the
+    // pongs are received by receive_data (which still returns them)
+    pub fn ping(&mut self) -> Result<(), packet::Error> {
+        let seq = self.next_ping;
+        self.next_ping += 1;
+        if self.sent_pings.len() == MAX_OUTSTANDING_PINGS {
+            self.sent_pings.pop_front();
+        }
+        self.sent_pings.push_back(SentPing {
+            seq,
+            sent: Instant::now(),
+            listener_answered: false,
+            mediator_answered: false,
+        });
+        self.send_request(&ToRobot::Ping(seq))
+    }
+
+    // None until a pong from source has been received. Comparing the two
+    // sources separates network latency from time spent waiting on the
+    // robot's main loop. Listener pongs include up to the robot's
+    // LoggerConfig::poll_interval before the ping is read
+    pub fn rtt(&self, source: PongSource) -> Option<RttStats> {
+        match source {
+            PongSource::Listener => self.listener_rtt.stats(),
+            PongSource::Mediator => self.mediator_rtt.stats(),
+        }
+    }
+
+    // how long the oldest ping still waiting on a pong from source has
+    // been waiting. A stalled main loop shows up here before any late
+    // PongSource::Mediator pong can
+    pub fn unanswered_for(&self, source: PongSource) -> Option<Duration> {
+        self.sent_pings
+            .iter()
+            .find(|ping| !ping.answered(source))
+            .map(|ping| ping.sent.elapsed())
+    }
+
+    // calls R on the robot and blocks until it responds or timeout passes.
+    // Anything else received meanwhile is returned by the next receive_data
+    pub fn call<R: Rpc>(
+        &mut self,
+        req: &R::Request,
+        timeout: Duration,
+    ) -> Result<R::Response, rpc::Error> {
+        let deadline = Instant::now() + timeout;
+        let id = self.send_call::<R>(req)?;
+        loop {
+            let mut response = None;
+            for pkt in self.read_packets()? {
+                match pkt {
+                    ToClient::Response { id: res_id, result } if res_id == id => {
+                        response = Some(result)
+                    }
+                    pkt => self.queued.push(pkt),
+                }
+            }
+            if let Some(result) = response {
+                return rpc::decode::<R>(result);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(rpc::Error::Timeout);
+            }
+            std::thread::sleep(remaining.min(CALL_POLL_INTERVAL));
+        }
+    }
+
+    // calls R on the robot without blocking. callback is run by
+    // receive_data once the response arrives or timeout passes, it is
+    // never run if the connection is lost first
+    pub fn call_with<R: Rpc>(
+        &mut self,
+        req: &R::Request,
+        timeout: Duration,
+        callback: impl FnOnce(Result<R::Response, rpc::Error>) + Send + 'static,
+    ) -> Result<(), packet::Error> {
+        let id = self.send_call::<R>(req)?;
+        self.pending_calls.insert(
+            id,
+            PendingCall {
+                deadline: Instant::now() + timeout,
+                callback: Box::new(move |result| {
+                    callback(result.map_or(Err(rpc::Error::Timeout), rpc::decode::<R>))
+                }),
+            },
+        );
+        Ok(())
+    }
+
+    fn send_call<R: Rpc>(&mut self, req: &R::Request) -> Result<u64, packet::Error> {
+        let id = self.next_call_id;
+        self.next_call_id += 1;
+        self.send_request(&ToRobot::Request {
+            id,
+            method: R::METHOD.to_owned(),
+            payload: bincode::serialize(req)?,
+        })?;
+        Ok(id)
+    }
+
+    fn read_packets(&mut self) -> Result<Vec<ToClient>, packet::Error> {
+        if let Some(e) = self.pending_error.take() {
+            return Err(self.disconnected(e));
+        }
         let mut pkts = Vec::new();
         let mut pkt_fn = |_: &mut _, pkt| -> Result<(), packet::Error> {
             pkts.push(pkt);
             Ok(())
         };
-        packet::recieve_multiple(&mut self.stream, &mut pkt_fn)?;
+        let res = packet::recieve_multiple(&mut self.stream, &mut self.decoder, &mut pkt_fn);
+        if !pkts.is_empty() {
+            self.last_received = Instant::now();
+        }
+        pkts.retain(|pkt| *pkt != ToClient::Heartbeat);
+        for pkt in &pkts {
+            if let ToClient::Pong { seq, source } = pkt {
+                self.record_pong(*seq, *source);
+            }
+        }
+        match res.and_then(|()| self.check_heartbeat()) {
+            // don't lose the packets sent right before the robot hung up
+            Err(e) if !pkts.is_empty() => self.pending_error = Some(e),
+            Err(e) => return Err(self.disconnected(e)),
+            Ok(()) => {}
+        }
         Ok(pkts)
     }
 
-    pub fn send_request(&mut self, pkt: &ToRobot) -> Result<(), packet::Error> {
-        packet::send(&mut self.stream, &pkt)
+    // checked after reading so the robot is never timed out while its
+    // packets are waiting to be read
+    fn check_heartbeat(&mut self) -> Result<(), packet::Error> {
+        let silent_for = self.last_received.elapsed();
+        if silent_for > self.heartbeat.timeout {
+            return Err(packet::Error::HeartbeatTimeout(silent_for));
+        }
+        // anything sent counts as a heartbeat
+        if self.last_sent.elapsed() >= self.heartbeat.interval {
+            self.send_request(&ToRobot::Heartbeat)?;
+        }
+        Ok(())
+    }
+
+    fn disconnected(&mut self, e: packet::Error) -> packet::Error {
+        self.state = ConnectionState::Disconnected;
+        e
+    }
+
+    fn record_pong(&mut self, seq: u64, source: PongSource) {
+        let Some(ping) = self.sent_pings.iter_mut().find(|ping| ping.seq == seq) else {
+            return;
+        };
+        let answered = ping.answered_mut(source);
+        if *answered {
+            return;
+        }
+        *answered = true;
+        let rtt = ping.sent.elapsed();
+        match source {
+            PongSource::Listener => self.listener_rtt.push(rtt),
+            PongSource::Mediator => self.mediator_rtt.push(rtt),
+        }
+        // both pongs are in so it can be forgotten
+        self.sent_pings
+            .retain(|ping| !(ping.listener_answered && ping.mediator_answered));
+    }
+
+    // runs the callback a response is for, other packets are returned.
+    // Responses to calls that already timed out are dropped
+    fn answer_call(&mut self, pkt: ToClient) -> Option<ToClient> {
+        match pkt {
+            ToClient::Response { id, result } => {
+                if let Some(call) = self.pending_calls.remove(&id) {
+                    (call.callback)(Some(result));
+                }
+                None
+            }
+            pkt => Some(pkt),
+        }
     }
+
+    fn expire_calls(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .pending_calls
+            .iter()
+            .filter(|(_, call)| call.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            if let Some(call) = self.pending_calls.remove(&id) {
+                (call.callback)(None);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ClientEvent {
+    StateChanged(ConnectionState),
+    Packet(ToClient),
+}
+
+// Client that never blocks for long and transparently reconnects (and
+// re-requests logs) whenever the connection to the robot is lost, e.g. when
+// the robot reboots. Call poll regularly to drive it
+pub struct ReconnectingClient {
+    addrs: Vec<SocketAddr>,
+    backoff: Backoff,
+    client: Option<Client>,
+    max_frame_len: usize,
+    heartbeat: Heartbeat,
+    failed_attempts: u32,
+    next_attempt: Instant,
+}
+
+impl ReconnectingClient {
+    pub fn new<A: ToSocketAddrs>(addr: A, backoff: Backoff) -> Result<Self, Error> {
+        Ok(Self {
+            addrs: resolve(addr)?,
+            backoff,
+            client: None,
+            max_frame_len: packet::DEFAULT_MAX_FRAME_LEN,
+            heartbeat: Heartbeat::default(),
+            failed_attempts: 0,
+            next_attempt: Instant::now(),
+        })
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        match self.client {
+            Some(_) => ConnectionState::Connected,
+            None => ConnectionState::Disconnected,
+        }
+    }
+
+    // applies to the current connection and any future reconnections
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len;
+        if let Some(client) = &mut self.client {
+            client.set_max_frame_len(max_frame_len);
+        }
+    }
+
+    // applies to the current connection and any future reconnections. The
+    // robot timing out is treated like any other disconnect
+    pub fn set_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.heartbeat = heartbeat;
+        if let Some(client) = &mut self.client {
+            client.set_heartbeat(heartbeat);
+        }
+    }
+
+    // the underlying client if currently connected
+    pub fn client(&mut self) -> Option<&mut Client> {
+        self.client.as_mut()
+    }
+
+    // attempts to (re)connect if disconnected and an attempt is due, then
+    // returns any state changes and received packets in order
+    pub fn poll(&mut self) -> Vec<ClientEvent> {
+        let mut events = Vec::new();
+
+        if self.client.is_none() && Instant::now() >= self.next_attempt {
+            // the handshake is bounded too so a robot that accepts but
+            // never answers can't stall the caller
+            match connect_any(&self.addrs, RECONNECT_ATTEMPT_TIMEOUT)
+                .map_err(Error::from)
+                .and_then(|stream| Client::from_stream(stream, RECONNECT_ATTEMPT_TIMEOUT))
+            {
+                Ok(mut client) => {
+                    client.set_max_frame_len(self.max_frame_len);
+                    client.set_heartbeat(self.heartbeat);
+                    self.client = Some(client);
+                    self.failed_attempts = 0;
+                    events.push(ClientEvent::StateChanged(ConnectionState::Connected));
+                }
+                Err(_) => {
+                    self.next_attempt = Instant::now() + self.backoff.delay(self.failed_attempts);
+                    self.failed_attempts += 1;
+                }
+            }
+        }
+
+        if let Some(client) = &mut self.client {
+            match client.receive_data() {
+                Ok(pkts) => events.extend(pkts.into_iter().map(ClientEvent::Packet)),
+                Err(_) => self.disconnect(&mut events),
+            }
+        }
+
+        events
+    }
+
+    // a failed send isn't treated as a disconnect here, the next poll will
+    // notice the broken connection and start reconnecting
+    pub fn send_request(&mut self, pkt: &ToRobot) -> Result<(), Error> {
+        let client = self.client.as_mut().ok_or(Error::NotConnected)?;
+        Ok(client.send_request(pkt)?)
+    }
+
+    fn disconnect(&mut self, events: &mut Vec<ClientEvent>) {
+        self.client = None;
+        self.next_attempt = Instant::now();
+        events.push(ClientEvent::StateChanged(ConnectionState::Disconnected));
+    }
+}
+
+fn resolve<A: ToSocketAddrs>(addr: A) -> Result<Vec<SocketAddr>, Error> {
+    let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+    if addrs.is_empty() {
+        return Err(Error::NoAddress);
+    }
+    Ok(addrs)
+}
+
+fn connect_any(addrs: &[SocketAddr], timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| std::io::ErrorKind::AddrNotAvailable.into()))
+}
+
+fn is_retryable(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::TimedOut | ErrorKind::WouldBlock
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // an address nothing is listening on
+    fn unused_addr() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
     #[test]
     fn blocking() {
-        let thread = std::thread::spawn(|| {
-            // listener will never be on port 9999
-            // so this will fail to connect
-            Client::new("127.0.0.1:9999").unwrap();
+        let addr = unused_addr();
+        let thread = std::thread::spawn(move || {
+            // this will keep failing to connect
+            Client::new(addr).unwrap();
         });
         std::thread::sleep(std::time::Duration::from_millis(15));
         assert!(!thread.is_finished());
     }
+
+    #[test]
+    fn backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+            multiplier: 2.0,
+        };
+        assert_eq!(backoff.delay(0), Duration::from_millis(10));
+        assert_eq!(backoff.delay(1), Duration::from_millis(20));
+        assert_eq!(backoff.delay(2), Duration::from_millis(40));
+        assert_eq!(backoff.delay(3), Duration::from_millis(50));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_millis(50));
+
+        let negative = Backoff {
+            multiplier: -2.0,
+            ..backoff
+        };
+        assert_eq!(negative.delay(1), Duration::ZERO);
+        assert_eq!(negative.delay(2), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn rtt_stats() {
+        let mut window = RttWindow::default();
+        assert_eq!(window.stats(), None);
+        for ms in [10, 30, 20] {
+            window.push(Duration::from_millis(ms));
+        }
+        assert_eq!(
+            window.stats(),
+            Some(RttStats {
+                last: Duration::from_millis(20),
+                min: Duration::from_millis(10),
+                avg: Duration::from_millis(20),
+                max: Duration::from_millis(30),
+                jitter: Duration::from_millis(15),
+                samples: 3,
+            })
+        );
+
+        for _ in 0..RTT_WINDOW {
+            window.push(Duration::from_millis(5));
+        }
+        let stats = window.stats().unwrap();
+        assert_eq!(stats.samples, RTT_WINDOW);
+        assert_eq!(stats.max, Duration::from_millis(5));
+        assert_eq!(stats.jitter, Duration::ZERO);
+    }
+
+    #[test]
+    fn connect_timeout() {
+        let addr = unused_addr();
+        let start = Instant::now();
+        let res = Client::connect_timeout(addr, Duration::from_millis(100));
+        assert!(matches!(res, Err(Error::Timeout)));
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn handshake_times_out() {
+        // connections are queued by the OS but never answered
+        let robot = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = robot.local_addr().unwrap();
+
+        let start = Instant::now();
+        let res = Client::connect_timeout(addr, Duration::from_millis(100));
+        assert!(matches!(res, Err(Error::Timeout)));
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let mut client = ReconnectingClient::new(addr, Backoff::default()).unwrap();
+        let start = Instant::now();
+        assert!(client.poll().is_empty());
+        assert!(start.elapsed() < RECONNECT_ATTEMPT_TIMEOUT * 3);
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+    }
+
+    #[test]
+    fn robot_times_out() {
+        let robot = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = robot.local_addr().unwrap();
+        // handshakes then goes silent, as if the robot lost power
+        let thread = std::thread::spawn(move || {
+            let mut stream = robot.accept().unwrap().0;
+            packet::handshake(&mut stream, Capabilities::ALL).unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+        });
+
+        // before connecting as the timeout counts from the handshake
+        let start = Instant::now();
+        let mut client = Client::new(addr).unwrap();
+        client.set_heartbeat(Heartbeat {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(50),
+        });
+        let res = loop {
+            match client.receive_data() {
+                Ok(_) if start.elapsed() < Duration::from_secs(1) => {}
+                res => break res,
+            }
+            std::thread::sleep(Duration::from_millis(2));
+        };
+        assert!(matches!(res, Err(packet::Error::HeartbeatTimeout(_))));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn reconnects() {
+        let robot = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = robot.local_addr().unwrap();
+
+        // accept, handshake and then drop the connection as if the robot rebooted
+        let thread = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let mut stream = robot.accept().unwrap().0;
+                packet::handshake(&mut stream, Capabilities::ALL).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let mut client = ReconnectingClient::new(addr, Backoff::default()).unwrap();
+        assert_eq!(client.state(), ConnectionState::Disconnected);
+
+        let mut states = Vec::new();
+        let start = Instant::now();
+        while states.len() < 3 && start.elapsed() < Duration::from_secs(2) {
+            for event in client.poll() {
+                if let ClientEvent::StateChanged(state) = event {
+                    states.push(state);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        thread.join().unwrap();
+
+        assert_eq!(
+            states,
+            [
+                ConnectionState::Connected,
+                ConnectionState::Disconnected,
+                ConnectionState::Connected
+            ]
+        );
+    }
 }
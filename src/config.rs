@@ -0,0 +1,185 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{
+    packet::{Heartbeat, DEFAULT_MAX_FRAME_LEN},
+    plot::BufferSettings,
+    telemetry::OverflowPolicy,
+};
+
+pub const DEFAULT_PORT: u16 = 8733;
+const DEFAULT_BUFFER_SIZE: usize = 10_000;
+const DEFAULT_FILTER: &str = "debug,client::coprocessor::serial=info";
+const DEFAULT_MAX_LOGS: usize = 100_000;
+const DEFAULT_MAX_SPILL_LEN: u64 = 64 * 1024 * 1024;
+const DEFAULT_RECORD_FILE_LEN: u64 = 16 * 1024 * 1024;
+const DEFAULT_RECORD_MAX_FILES: usize = 16;
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// settings for Logger::new and Logger::init, the defaults match what the robot has always used
+// e.g. LoggerConfig::default().port(8734).first_robot(false)
+#[derive(Debug, Clone)]
+pub struct LoggerConfig {
+    pub(crate) first_robot: bool,
+    pub(crate) bind_address: IpAddr,
+    pub(crate) port: u16,
+    pub(crate) buffer_size: usize,
+    pub(crate) default_filter: String,
+    pub(crate) max_frame_len: usize,
+    pub(crate) mirror_to_stderr: bool,
+    pub(crate) max_logs: usize,
+    pub(crate) max_log_age: Option<Duration>,
+    pub(crate) log_spill_path: Option<PathBuf>,
+    pub(crate) max_spill_len: u64,
+    pub(crate) record_dir: Option<PathBuf>,
+    pub(crate) record_file_len: u64,
+    pub(crate) record_max_files: usize,
+    pub(crate) overflow_policy: OverflowPolicy,
+    pub(crate) plot_buffer: BufferSettings,
+    pub(crate) heartbeat: Heartbeat,
+    pub(crate) poll_interval: Duration,
+}
+
+impl Default for LoggerConfig {
+    fn default() -> Self {
+        Self {
+            first_robot: true,
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            default_filter: String::from(DEFAULT_FILTER),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            mirror_to_stderr: true,
+            max_logs: DEFAULT_MAX_LOGS,
+            max_log_age: None,
+            log_spill_path: None,
+            max_spill_len: DEFAULT_MAX_SPILL_LEN,
+            record_dir: None,
+            record_file_len: DEFAULT_RECORD_FILE_LEN,
+            record_max_files: DEFAULT_RECORD_MAX_FILES,
+            overflow_policy: OverflowPolicy::default(),
+            plot_buffer: BufferSettings::default(),
+            heartbeat: Heartbeat::default(),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+}
+
+// so that Logger::init(first_robot) keeps working
+impl From<bool> for LoggerConfig {
+    fn from(first_robot: bool) -> Self {
+        Self::default().first_robot(first_robot)
+    }
+}
+
+impl LoggerConfig {
+    pub fn first_robot(mut self, first_robot: bool) -> Self {
+        self.first_robot = first_robot;
+        self
+    }
+    // address the listener binds to, defaults to 0.0.0.0
+    pub fn bind_address(mut self, addr: impl Into<IpAddr>) -> Self {
+        self.bind_address = addr.into();
+        self
+    }
+    // 0 picks a free port, see Mediator::local_addr
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+    // capacity of each channel between the main thread and the listener thread
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+    // filter used when RUST_LOG isn't set, uses the env_logger syntax
+    pub fn default_filter(mut self, filter: impl Into<String>) -> Self {
+        self.default_filter = filter.into();
+        self
+    }
+    // clients sending a packet larger than this are disconnected
+    pub fn max_frame_len(mut self, len: usize) -> Self {
+        self.max_frame_len = len;
+        self
+    }
+
+    // whether logs are also printed locally with env_logger
+    pub fn mirror_to_stderr(mut self, mirror: bool) -> Self {
+        self.mirror_to_stderr = mirror;
+        self
+    }
+
+    // the listener keeps at most this many logs to send to clients that
+    // connect later, the oldest are evicted first
+    pub fn max_logs(mut self, max_logs: usize) -> Self {
+        self.max_logs = max_logs;
+        self
+    }
+    // logs older than this are evicted, by default logs never expire
+    pub fn max_log_age(mut self, age: Duration) -> Self {
+        self.max_log_age = Some(age);
+        self
+    }
+    // evicted logs are appended to this file instead of being discarded,
+    // it's a recording that recorder::read_recording and Replayer can read
+    pub fn spill_logs_to(mut self, path: impl Into<PathBuf>) -> Self {
+        self.log_spill_path = Some(path.into());
+        self
+    }
+    // once the spill file (including anything already in it) reaches this
+    // many bytes evicted logs are discarded again
+    pub fn max_spill_len(mut self, len: u64) -> Self {
+        self.max_spill_len = len;
+        self
+    }
+    // records every log, plot buffer and odometry sample to files in dir
+    // whether or not a client is connected, see recorder.rs
+    pub fn record_to(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.record_dir = Some(dir.into());
+        self
+    }
+    // a new recording file is started once the current one reaches this size
+    pub fn record_file_len(mut self, len: u64) -> Self {
+        self.record_file_len = len;
+        self
+    }
+    // the oldest recording files are deleted beyond this many
+    pub fn record_max_files(mut self, max_files: usize) -> Self {
+        self.record_max_files = max_files;
+        self
+    }
+    // what happens to logs, plot points and odometry when the listener falls
+    // behind, defaults to OverflowPolicy::DropNewest
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+    // when plot points are sent unless overridden with plot::configure,
+    // defaults to every 50 points or 100ms
+    pub fn plot_buffer(mut self, settings: BufferSettings) -> Self {
+        self.plot_buffer = settings;
+        self
+    }
+    // how often clients are sent heartbeats and how long a silent client is
+    // kept before being disconnected. The clients' timeout should be longer
+    // than this interval
+    pub fn heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+    // how long the listener waits on the main thread before reading the
+    // sockets, so requests from clients (pings included) can wait up to this
+    // long while nothing is being logged. Shorter uses more CPU, defaults
+    // to 5ms
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    pub(crate) fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+}
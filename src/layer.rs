@@ -0,0 +1,184 @@
+use crate::{
+    log_filter::SharedFilter,
+    packet::{ExtendedLog, Fields, FromMediator, SimpleLog, SpanContext, Value},
+    telemetry::Sink,
+};
+use std::{sync::Arc, time::SystemTime};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+// forwards tracing events to the listener as ToClient::ExtendedLog with
+// their fields and span context. Get one from Logger::tracing_layer.
+// Events are filtered by the logger's filter like any other log
+pub struct NetworkLayer {
+    sink: Sink,
+    filter: Arc<SharedFilter>,
+}
+
+impl NetworkLayer {
+    pub(crate) fn new(sink: Sink, filter: Arc<SharedFilter>) -> Self {
+        Self { sink, filter }
+    }
+}
+
+impl<S> Layer<S> for NetworkLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        span.extensions_mut().insert(visitor.fields);
+    }
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<Fields>() {
+            let mut visitor = FieldVisitor {
+                fields: std::mem::take(fields),
+                message: None,
+            };
+            values.record(&mut visitor);
+            *fields = visitor.fields;
+        }
+    }
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let level = to_log_level(*metadata.level());
+        let log_metadata = log::Metadata::builder()
+            .level(level)
+            .target(metadata.target())
+            .build();
+        if !self.filter.enabled(&log_metadata) {
+            return;
+        }
+
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+
+        let spans = ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| SpanContext {
+                name: span.name().to_owned(),
+                target: span.metadata().target().to_owned(),
+                fields: span
+                    .extensions()
+                    .get::<Fields>()
+                    .cloned()
+                    .unwrap_or_default(),
+            })
+            .collect();
+
+        let log = SimpleLog {
+            level,
+            msg: visitor.message.unwrap_or_default(),
+            target: metadata.target().to_owned(),
+            timestamp: SystemTime::now(),
+            module_path: metadata.module_path().map(str::to_owned),
+            file: metadata.file().map(str::to_owned),
+            line: metadata.line(),
+            fields: visitor.fields,
+        };
+        let _ = self
+            .sink
+            .send(FromMediator::ExtendedLog(Box::new(ExtendedLog {
+                log,
+                spans,
+            })));
+    }
+}
+
+fn to_log_level(level: tracing::Level) -> log::Level {
+    match level {
+        tracing::Level::ERROR => log::Level::Error,
+        tracing::Level::WARN => log::Level::Warn,
+        tracing::Level::INFO => log::Level::Info,
+        tracing::Level::DEBUG => log::Level::Debug,
+        tracing::Level::TRACE => log::Level::Trace,
+    }
+}
+
+// collects fields keeping the "message" field of events separate
+#[derive(Default)]
+struct FieldVisitor {
+    fields: Fields,
+    message: Option<String>,
+}
+
+impl FieldVisitor {
+    fn insert(&mut self, field: &Field, value: Value) {
+        self.fields.insert(field.name().to_owned(), value);
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, Value::Bool(value));
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, Value::I64(value));
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, Value::U64(value));
+    }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, Value::F64(value));
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, Value::Str(value.to_owned()));
+    }
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message = Some(format!("{value:?}"));
+        } else {
+            self.insert(field, Value::Str(format!("{value:?}")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::bounded;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn fields_and_spans() {
+        let (tx, rx) = bounded(16);
+        let filter = Arc::new(SharedFilter::new("warn"));
+        let subscriber =
+            tracing_subscriber::registry().with(NetworkLayer::new(Sink::new(tx), filter));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("drive", motor = 3u64, reversed = false);
+            let _guard = span.enter();
+            span.record("reversed", true);
+            tracing::info!("filtered out");
+            tracing::warn!(speed = 1.5, side = "left", "stalled");
+        });
+
+        let Ok(FromMediator::ExtendedLog(log)) = rx.try_recv() else {
+            panic!("expected an extended log");
+        };
+        assert_eq!(log.log.msg, "stalled");
+        assert_eq!(log.log.level, log::Level::Warn);
+        assert_eq!(log.log.fields["speed"], Value::F64(1.5));
+        assert_eq!(log.log.fields["side"], Value::Str(String::from("left")));
+        assert!(!log.log.fields.contains_key("message"));
+
+        assert_eq!(log.spans.len(), 1);
+        assert_eq!(log.spans[0].name, "drive");
+        assert_eq!(log.spans[0].fields["motor"], Value::U64(3));
+        assert_eq!(log.spans[0].fields["reversed"], Value::Bool(true));
+    }
+}
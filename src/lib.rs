@@ -1,23 +1,48 @@
-use crossbeam_channel::{bounded, RecvError, SendError, Sender};
+use crossbeam_channel::{bounded, RecvError, SendError};
 use log::{Log, Metadata, Record};
-use packet::{FromMediator, ToMediator};
+use packet::ToMediator;
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 pub mod client;
+pub mod config;
+#[cfg(feature = "tracing")]
+pub mod layer;
 pub mod listener;
+mod log_filter;
+mod log_store;
 pub mod mediator;
 pub mod packet;
+pub mod params;
 pub mod path;
+pub mod pid;
 pub mod plot;
+pub mod recorder;
+pub mod replay;
+pub mod rpc;
+pub mod telemetry;
 
+pub use config::LoggerConfig;
 use listener::Listener;
+use log_filter::SharedFilter;
 pub use mediator::Mediator;
 pub use packet::{SimpleLog, ToClient};
 
-const MPSC_BUFFER_SIZE: usize = 10_000;
+// the first_robot setting of the logger installed by Logger::init, loggers
+// created with Logger::new keep their own
 pub static FIRST_ROBOT: AtomicBool = AtomicBool::new(true);
 
+#[derive(thiserror::Error, Debug)]
+pub enum InitError {
+    #[error("failed to bind the listener, is another instance running?\n{0}")]
+    Bind(#[from] std::io::Error),
+    #[error("a global logger is already installed:\n{0}")]
+    SetLogger(#[from] log::SetLoggerError),
+}
+
 #[derive(thiserror::Error, Debug)]
 enum Error {
     #[error("IO error:\n{0}")]
@@ -32,99 +57,257 @@ enum Error {
     Mediator(#[from] mediator::Error),
 }
 
-// also handles plotting
-pub struct Logger {
-    sender: Sender<FromMediator>,
-    local_logger: env_logger::Logger,
+// state shared by a Logger, its Mediator and its listener thread
+#[derive(Clone)]
+pub(crate) struct Shared {
+    pub(crate) filter: Arc<SharedFilter>,
+    pub(crate) dropped: Arc<telemetry::DropCounters>,
+    pub(crate) pids: Arc<pid::PidRegistry>,
+    pub(crate) params: Arc<params::ParamRegistry>,
+    pub(crate) clients: Arc<listener::ClientRegistry>,
+    // for OverflowPolicy::DropOldest, closed when the listener exits
+    pub(crate) sink_receiver: Arc<telemetry::SinkReceiver>,
 }
 
-impl Logger {
-    pub fn init(is_first_robot: bool) -> Result<Mediator, log::SetLoggerError> {
-        let (thread_tx, main_rx) = bounded(MPSC_BUFFER_SIZE);
-        let (main_tx, thread_rx) = bounded(MPSC_BUFFER_SIZE);
-
-        FIRST_ROBOT.store(is_first_robot, Ordering::Relaxed);
-
-        Listener::spawn(thread_tx, thread_rx);
-
-        // set default log level
-        if std::env::var("RUST_LOG").is_err() {
-            std::env::set_var("RUST_LOG", "debug,client::coprocessor::serial=info");
+impl Shared {
+    pub(crate) fn new(filter: &str) -> Self {
+        Self {
+            filter: Arc::new(SharedFilter::new(filter)),
+            dropped: Arc::default(),
+            pids: Arc::default(),
+            params: Arc::default(),
+            clients: Arc::default(),
+            sink_receiver: Arc::default(),
         }
+    }
+}
 
-        let local_logger = env_logger::Logger::from_default_env();
+// sends logs to the listener thread and optionally mirrors them to stderr
+// with env_logger. Logger::init installs it as the global logger, otherwise
+// Logger::new returns it as a plain log::Log that can be combined with other
+// loggers
+pub struct Logger {
+    sink: telemetry::Sink,
+    filter: Arc<SharedFilter>,
+    local_logger: Option<env_logger::Logger>,
+}
 
-        let filter = local_logger.filter();
+impl Logger {
+    // creates the logger and starts the listener without touching any
+    // global state. Errors if the listener can't bind its address
+    pub fn new(config: impl Into<LoggerConfig>) -> std::io::Result<(Self, Mediator)> {
+        let config = config.into();
+        let tcp = Listener::bind(&config)?;
+        let local_addr = tcp.local_addr()?;
+        let (thread_tx, main_rx) = bounded(config.buffer_size);
+        let (main_tx, thread_rx) = bounded(config.buffer_size);
+        let (telemetry_tx, telemetry_rx) = bounded(config.buffer_size);
+
+        let filters = std::env::var("RUST_LOG").unwrap_or_else(|_| config.default_filter.clone());
+        let mut shared = Shared::new(&filters);
+        shared.sink_receiver = Arc::new(telemetry::SinkReceiver::new(telemetry_rx.clone()));
+        // records are filtered before reaching env_logger so that it follows
+        // any runtime changes to the filter
+        let local_logger = config.mirror_to_stderr.then(|| {
+            env_logger::Builder::new()
+                .filter_level(log::LevelFilter::Trace)
+                .build()
+        });
 
-        log::set_boxed_logger(Box::new(Self {
-            sender: main_tx.clone(),
+        let sink = telemetry::Sink::with_policy(
+            telemetry_tx,
+            main_tx.clone(),
+            shared.sink_receiver.clone(),
+            config.overflow_policy,
+            shared.dropped.clone(),
+        );
+        let listener = Listener::spawn(
+            tcp,
+            thread_tx,
+            thread_rx,
+            telemetry_rx,
+            config,
+            shared.clone(),
+        );
+
+        let logger = Self {
+            sink,
+            filter: shared.filter.clone(),
             local_logger,
-        }))
-        .map(|()| log::set_max_level(filter))?;
-
-        unsafe {
-            crate::plot::PLOTTER = Some(main_tx.clone());
         };
+        let mediator = Mediator::new(main_tx, main_rx, listener, local_addr, shared);
+        Ok((logger, mediator))
+    }
+    // creates the logger, installs it as the global logger and installs the
+    // global telemetry sink for plot! and odom. Takes a LoggerConfig or
+    // whether this is the first robot
+    pub fn init(config: impl Into<LoggerConfig>) -> Result<Mediator, InitError> {
+        let config = config.into();
+        let first_robot = config.first_robot;
+        let (logger, mediator) = Self::new(config)?;
+
+        let sink = logger.telemetry_sink();
+        let filter = logger.filter.clone();
+        if let Err(e) = log::set_boxed_logger(Box::new(logger)) {
+            // the listener would otherwise outlive the logger feeding it
+            let _ = mediator.shutdown();
+            return Err(e.into());
+        }
+        filter.set_global();
+        FIRST_ROBOT.store(first_robot, Ordering::Relaxed);
 
-        Ok(Mediator::new(main_tx, main_rx))
+        if telemetry::install(sink).is_err() {
+            log::warn!("A telemetry sink was already installed, plots and odometry won't be sent to clients.");
+        }
+
+        Ok(mediator)
+    }
+    // the most verbose level currently allowed by the filter, useful when
+    // setting log::set_max_level for a logger that wraps this one. Clients
+    // can change it with ToRobot::SetLogFilter
+    pub fn max_level(&self) -> log::LevelFilter {
+        self.filter.max_level()
+    }
+    // a sink sending plots and odometry to this logger's listener
+    pub fn telemetry_sink(&self) -> telemetry::Sink {
+        self.sink.clone()
+    }
+    // logs, plot points and odometry discarded so far because the listener
+    // couldn't keep up (see LoggerConfig::overflow_policy), and events
+    // discarded because the main loop couldn't
+    pub fn dropped(&self) -> telemetry::Dropped {
+        self.sink.dropped()
+    }
+    // a tracing_subscriber::Layer sending events to this logger's listener
+    #[cfg(feature = "tracing")]
+    pub fn tracing_layer(&self) -> layer::NetworkLayer {
+        layer::NetworkLayer::new(self.sink.clone(), self.filter.clone())
     }
 }
 
 impl Log for Logger {
-    fn enabled(&self, _: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
     }
     fn log(&self, record: &Record) {
-        let _ = self.sender.try_send(record.into());
-        self.local_logger.log(record);
+        if !self.filter.matches(record) {
+            return;
+        }
+        let _ = self.sink.send(record.into());
+        if let Some(local_logger) = &self.local_logger {
+            local_logger.log(record);
+        }
     }
     fn flush(&self) {
-        self.local_logger.flush();
+        if let Some(local_logger) = &self.local_logger {
+            local_logger.flush();
+        }
     }
 }
 
 pub fn odom(pos: impl Into<[f64; 2]>, heading: impl Into<f64>) {
-    if let Some(sender) = unsafe { &*std::ptr::addr_of!(plot::PLOTTER) } {
-        let _ = sender.try_send(packet::FromMediator::Odometry((pos.into(), heading.into())));
+    if let Some(sink) = telemetry::sink() {
+        let _ = sink.send(packet::FromMediator::Odometry((pos.into(), heading.into())));
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::client::Client;
-    use crate::packet::ToRobot;
+    use crate::packet::{FromMediator, PongSource};
 
     use super::*;
 
+    // a listener on config's address with a mediator for it, stopped with
+    // Mediator::shutdown. Its log filter lets everything through
+    pub(crate) fn spawn_listener(config: LoggerConfig) -> (Mediator, Shared) {
+        let tcp = Listener::bind(&config).unwrap();
+        let local_addr = tcp.local_addr().unwrap();
+        let (thread_tx, main_rx) = bounded(config.buffer_size);
+        let (main_tx, thread_rx) = bounded(config.buffer_size);
+        let shared = Shared::new("trace");
+        // tests send their telemetry through the mediator instead of a sink
+        let listener = Listener::spawn(
+            tcp,
+            thread_tx,
+            thread_rx,
+            crossbeam_channel::never(),
+            config,
+            shared.clone(),
+        );
+        let mediator = Mediator::new(main_tx, main_rx, listener, local_addr, shared.clone());
+        (mediator, shared)
+    }
+
+    // the other tests run alongside this one and their listeners log
+    // through the global logger, plot! included, so clients of the global
+    // logger only count this test's logs, plots and odometry
+    fn from_this_test(pkts: Vec<ToClient>) -> Vec<ToClient> {
+        pkts.into_iter()
+            .filter(|pkt| match pkt {
+                ToClient::Log(log) => log.target == module_path!(),
+                ToClient::PointBuffer(((plot, _), _)) => plot.starts_with("test_plot"),
+                ToClient::Odometry(_) | ToClient::Pong { .. } => true,
+                _ => false,
+            })
+            .collect()
+    }
+
     #[test]
     fn logging() {
-        let mut mediator = Logger::init(true).unwrap();
+        // plots are flushed well before the clients below read them
+        let config = LoggerConfig::default()
+            .bind_address(std::net::Ipv4Addr::LOCALHOST)
+            .port(0)
+            .plot_buffer(plot::BufferSettings {
+                size: 50,
+                timeout: std::time::Duration::from_millis(20),
+            });
+        let mut mediator = Logger::init(config).unwrap();
+        let addr = mediator.local_addr();
 
         // START LOGGING TESTS
-        let client = std::thread::spawn(|| {
+        let client = std::thread::spawn(move || {
             // to make sure that the TcpListener is bound to port
             std::thread::sleep(std::time::Duration::from_millis(20));
 
             // create client after logs have been sent
-            let mut client = Client::new("127.0.0.1:8733").unwrap();
+            let mut client = Client::new(addr).unwrap();
 
             // give time for robot to respond
             // also see https://github.com/EMU5-Robotics/communication/issues/3
             std::thread::sleep(std::time::Duration::from_millis(50));
 
-            let pkts = client.receive_data().unwrap();
+            let pkts = from_this_test(client.receive_data().unwrap());
 
-            assert_eq!(pkts.len(), 6); //extra log for "Client connected"
+            assert_eq!(pkts.len(), 5, "{pkts:#?}");
 
-            client.send_request(&ToRobot::Ping).unwrap();
+            client.ping().unwrap();
 
             // give ping chain time to complete
             std::thread::sleep(std::time::Duration::from_millis(50));
 
-            // client should of received only Pong packet
-            let pkts = client.receive_data().unwrap();
-            assert_eq!(pkts.len(), 1);
-            assert_eq!(pkts[0], ToClient::Pong);
+            // client should of received only the Pong packets, the listener
+            // answers before the main loop can
+            let pkts = from_this_test(client.receive_data().unwrap());
+            assert_eq!(
+                pkts,
+                [
+                    ToClient::Pong {
+                        seq: 0,
+                        source: PongSource::Listener
+                    },
+                    ToClient::Pong {
+                        seq: 0,
+                        source: PongSource::Mediator
+                    }
+                ]
+            );
+            let listener = client.rtt(PongSource::Listener).unwrap();
+            let mediator = client.rtt(PongSource::Mediator).unwrap();
+            assert_eq!(listener.samples, 1);
+            assert!(listener.last <= mediator.last);
+            assert_eq!(client.unanswered_for(PongSource::Mediator), None);
         });
 
         // check logging
@@ -140,8 +323,11 @@ mod tests {
             };
 
             for event in events {
+                #[allow(clippy::single_match)]
                 match event {
-                    ToMediator::Ping => mediator.send_event(FromMediator::Pong).unwrap(),
+                    ToMediator::Ping(ping) => {
+                        mediator.send_event(FromMediator::Pong(ping)).unwrap()
+                    }
                     _ => {}
                 }
             }
@@ -152,19 +338,31 @@ mod tests {
         // END LOGGING TESTS
 
         // START PLOTTING + ODOM TESTS
-        let client = std::thread::spawn(|| {
-            // create client after logs have been sent
-            let mut client = Client::new("127.0.0.1:8733").unwrap();
+        // two clients at once, each should receive everything
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(2));
+        let clients: Vec<_> = (0..2)
+            .map(|_| {
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    // create client after logs have been sent
+                    let mut client = Client::new(addr).unwrap();
 
-            // give time for robot to respond
-            std::thread::sleep(std::time::Duration::from_millis(150));
+                    // give time for robot to respond
+                    std::thread::sleep(std::time::Duration::from_millis(150));
 
-            let pkts = client.receive_data().unwrap();
+                    let pkts = from_this_test(client.receive_data().unwrap());
 
-            assert_eq!(pkts.len(), 6); // including disconnect and connect log
-        });
+                    // full log history, 3 plot buffers and odometry
+                    assert_eq!(pkts.len(), 9);
 
-        std::thread::sleep(std::time::Duration::from_millis(10));
+                    // don't disconnect until the other client has read its packets
+                    barrier.wait();
+                })
+            })
+            .collect();
+
+        // make sure both clients are connected before plotting
+        std::thread::sleep(std::time::Duration::from_millis(50));
 
         // check logging
         plot!("test_plot", 5);
@@ -187,6 +385,56 @@ mod tests {
             // fancy busy loop simulation
             std::thread::sleep(std::time::Duration::from_millis(2));
         }
-        client.join().unwrap();
+        for client in clients {
+            client.join().unwrap();
+        }
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn without_global_logger() {
+        let config = LoggerConfig::default()
+            .bind_address(std::net::Ipv4Addr::LOCALHOST)
+            .port(0)
+            .mirror_to_stderr(false);
+        let (logger, mediator) = Logger::new(config).unwrap();
+
+        let mut client = Client::new(mediator.local_addr()).unwrap();
+        // below the default filter so neither enabled nor sent
+        let trace = Metadata::builder()
+            .level(log::Level::Trace)
+            .target("test")
+            .build();
+        assert!(!logger.enabled(&trace));
+        logger.log(
+            &Record::builder()
+                .args(format_args!("filtered"))
+                .metadata(trace)
+                .build(),
+        );
+        logger.log(
+            &Record::builder()
+                .args(format_args!("not global"))
+                .level(log::Level::Warn)
+                .target("test")
+                .build(),
+        );
+        mediator.shutdown().unwrap();
+
+        let pkts = client.receive_data().unwrap();
+        assert!(
+            matches!(pkts.as_slice(), [ToClient::Log(log)] if log.msg == "not global"),
+            "{pkts:?}"
+        );
+    }
+
+    #[test]
+    fn reports_bind_errors() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = LoggerConfig::default()
+            .bind_address(std::net::Ipv4Addr::LOCALHOST)
+            .port(taken.local_addr().unwrap().port());
+        assert!(Logger::new(config).is_err());
     }
 }
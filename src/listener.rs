@@ -1,145 +1,1393 @@
 use crate::{
-    packet::{self, FromMediator, ToClient, ToMediator, ToRobot},
-    plot::PlotManager,
-    Error, FIRST_ROBOT,
+    log_store::LogStore,
+    packet::{
+        self, Capabilities, ClientInfo, FrameDecoder, FromMediator, Heartbeat, Hello, Ping,
+        PongSource, ToClient, ToMediator, ToRobot,
+    },
+    plot::{PlotManager, PlotSettings},
+    recorder::Recorder,
+    rpc::{self, ClientId},
+    telemetry::{self, Dropped},
+    Error, LoggerConfig, Shared, SimpleLog,
 };
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, RecvError, SendError, Sender, TrySendError};
+use log::LevelFilter;
 use std::{
-    collections::VecDeque,
-    net::{TcpListener, TcpStream},
+    collections::{BTreeMap, HashSet},
+    io::Write,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
+// upper bound on packets from the mediator handled per loop iteration so a
+// flood of logs can't starve the sockets
+const MAX_PACKETS_PER_POLL: usize = 256;
+// how often clients are told about telemetry dropped since the last notice
+const DROP_NOTICE_INTERVAL: Duration = Duration::from_secs(1);
+// logs are held back in the LogStore once this many bytes are waiting to be
+// written to a client, so a slow client catches up from its log cursor
+const MAX_QUEUED_LOGS_LEN: usize = 64 << 10;
+// a client with more than this many bytes waiting to be written isn't
+// keeping up and is disconnected
+const MAX_OUTGOING_LEN: usize = 8 << 20;
+// how long shutdown waits for the clients to take what is still queued
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub(crate) struct Listener {
     tx: Sender<ToMediator>,
     rx: Receiver<FromMediator>,
-    was_connected: bool,
+    // logs, plot points and odometry from the sinks, see telemetry::Sink
+    telemetry: Receiver<FromMediator>,
     tcp: TcpListener,
-    logs: Vec<ToClient>,
-    last_log: usize,
-    packet_buffer: VecDeque<FromMediator>,
+    max_frame_len: usize,
+    heartbeat: Heartbeat,
+    // see LoggerConfig::poll_interval
+    poll_interval: Duration,
+    // sent with odometry, see LoggerConfig::first_robot
+    first_robot: bool,
+    logs: LogStore,
+    clients: Vec<Connection>,
+    // accepted but still waiting on their Hello
+    pending: Vec<PendingClient>,
+    // id given to the next client that connects
+    next_client_id: u64,
+    recorder: Option<Recorder>,
+    plot_settings: PlotSettings,
+    shared: Shared,
+    // drops already reported to clients
+    reported_dropped: Dropped,
+    last_drop_notice: Instant,
+    // set once the mediator asks the listener to shut down
+    stopped: bool,
+}
+
+// a client that has been sent our Hello, see Listener::accept_clients
+struct PendingClient {
+    stream: TcpStream,
+    // holds the client's Hello and anything sent after it
+    decoder: FrameDecoder,
+    addr: SocketAddr,
+    accepted: Instant,
+}
+
+// state kept for each connected client
+struct Connection {
+    id: ClientId,
+    stream: TcpStream,
+    decoder: FrameDecoder,
+    addr: SocketAddr,
+    capabilities: Capabilities,
+    // framed packets the socket hasn't taken yet, see Connection::send
+    outgoing: Vec<u8>,
+    // absolute index into Listener::logs of the next log to send
+    next_log: u64,
+    log_filter: LogFilter,
     plot_manager: PlotManager,
+    last_received: Instant,
+    last_heartbeat: Instant,
+    // parameters sent to this client whenever they change
+    subscriptions: HashSet<String>,
+}
+
+// which logs a client asked for with ToRobot::SetLogLevel and
+// ToRobot::SetLogTargets
+struct LogFilter {
+    level: LevelFilter,
+    targets: Vec<String>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Trace,
+            targets: Vec::new(),
+        }
+    }
+}
+
+impl LogFilter {
+    fn matches(&self, log: &SimpleLog) -> bool {
+        let module_path = log.module_path.as_deref().unwrap_or_default();
+        log.level <= self.level
+            && (self.targets.is_empty()
+                || self
+                    .targets
+                    .iter()
+                    .any(|target| in_module(&log.target, target) || in_module(module_path, target)))
+    }
+}
+
+// whether path is module or one of its submodules, so "drive" matches
+// "drive::motors" but not "drivetrain"
+fn in_module(path: &str, module: &str) -> bool {
+    path.strip_prefix(module)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
 }
 
 impl Listener {
-    pub(crate) fn spawn(tx: Sender<ToMediator>, rx: Receiver<FromMediator>) {
-        // note that we don't get the thread handle to join later since
-        // the listener thread can stall due to the static global logger
-        // having a Sender<FromMediator> which can prevent the listener
-        // loop from exiting
+    // bound before the thread is spawned so that errors reach the caller
+    pub(crate) fn bind(config: &LoggerConfig) -> std::io::Result<TcpListener> {
+        let tcp = TcpListener::bind(config.socket_addr())?;
+        tcp.set_nonblocking(true)?;
+        Ok(tcp)
+    }
+    // note that the sinks hold a Sender<FromMediator> for rx so the channel
+    // never disconnects, the thread only exits once it receives
+    // FromMediator::Shutdown (see Mediator::shutdown)
+    pub(crate) fn spawn(
+        tcp: TcpListener,
+        tx: Sender<ToMediator>,
+        rx: Receiver<FromMediator>,
+        telemetry: Receiver<FromMediator>,
+        config: LoggerConfig,
+        shared: Shared,
+    ) -> JoinHandle<()> {
         std::thread::spawn(move || {
-            if let Err(e) = Self::run(tx, rx) {
-                // this will log using only env_logger
-                log::error!("Listener thread errored with:\n{e}\nCommunication with clients is no longer possible. Is another instance running?");
-            }
-        });
+            telemetry::mark_listener_thread();
+            let sink_receiver = shared.sink_receiver.clone();
+            Self::new(tcp, tx, rx, telemetry, config, shared).run();
+            // the sinks now see a disconnected channel instead of a full one
+            sink_receiver.close();
+        })
     }
-    fn new(tx: Sender<ToMediator>, rx: Receiver<FromMediator>) -> Result<Self, Error> {
-        let tcp = TcpListener::bind("0.0.0.0:8733")?;
-        tcp.set_nonblocking(true)?;
+    fn new(
+        tcp: TcpListener,
+        tx: Sender<ToMediator>,
+        rx: Receiver<FromMediator>,
+        telemetry: Receiver<FromMediator>,
+        config: LoggerConfig,
+        shared: Shared,
+    ) -> Self {
+        let logs = LogStore::new(&config);
+        // clients are still served without a recording
+        let recorder = config.record_dir.as_ref().and_then(|dir| {
+            Recorder::new(
+                dir,
+                config.record_file_len,
+                config.record_max_files,
+                config.first_robot,
+            )
+            .map_err(|e| log::error!("Failed to start recording to {dir:?}, not recording: {e}"))
+            .ok()
+        });
 
-        Ok(Self {
+        Self {
             tx,
             rx,
-            // it should be common for the client to start after the robot
-            // so set was_connected=false so we don't warn about that
-            was_connected: false,
-            packet_buffer: VecDeque::new(),
-            last_log: 0,
-            logs: Vec::new(),
+            telemetry,
             tcp,
-            plot_manager: PlotManager::default(),
-        })
+            max_frame_len: config.max_frame_len,
+            heartbeat: config.heartbeat,
+            poll_interval: config.poll_interval,
+            first_robot: config.first_robot,
+            logs,
+            clients: Vec::new(),
+            pending: Vec::new(),
+            next_client_id: 0,
+            recorder,
+            plot_settings: PlotSettings::new(config.plot_buffer),
+            reported_dropped: shared.dropped.get(),
+            shared,
+            last_drop_notice: Instant::now(),
+            stopped: false,
+        }
     }
 
-    fn run(tx: Sender<ToMediator>, rx: Receiver<FromMediator>) -> Result<(), Error> {
-        let mut s = Self::new(tx, rx)?;
-        loop {
-            match s.read_from_mediator() {
+    fn run(mut self) {
+        while !self.stopped {
+            match self
+                .accept_clients()
+                .and_then(|()| self.read_from_mediator())
+                .and_then(|()| self.for_each_client(Self::poll_tcp_events))
+                .and_then(|()| self.notify_changed_params())
+                .and_then(|()| self.for_each_client(Self::process_plot_points))
+                .and_then(|()| self.for_each_client(Self::write_outgoing))
+            {
                 Err(Error::Recv(_) | Error::Send(_)) => break,
-                Err(_) if s.was_connected => {
-                    s.was_connected = false;
-                    log::warn!("Client disconnected since last packet.");
+                Err(e) => log::error!("Listener failed to process packet: {e}"),
+                Ok(()) => {}
+            }
+            self.with_recorder(Recorder::tick);
+            self.notify_dropped();
+        }
+
+        if !self.stopped {
+            log::error!(
+                "listener thread has exited. The application can no longer communicate with clients.\n\
+                 This message should be unreachable as the sender should never be dropped."
+            );
+        }
+        self.flush_and_close();
+    }
+    // sends anything still queued to the clients then hangs up on them
+    fn flush_and_close(&mut self) {
+        // a client whose handshake has finished on its end is owed
+        // everything queued too
+        let _ = self.poll_pending();
+        while let Some(pkt) = self.try_recv() {
+            if self.process_packet(pkt).is_err() {
+                break;
+            }
+        }
+        let _ = self.for_each_client(|_, client| Ok(client.flush_plots()?));
+        // including logs held back by send_logs
+        let deadline = Instant::now() + SHUTDOWN_FLUSH_TIMEOUT;
+        loop {
+            let _ = self.for_each_client(Self::write_outgoing);
+            if self.clients.iter().all(|c| self.caught_up(c)) || Instant::now() > deadline {
+                break;
+            }
+            std::thread::sleep(self.poll_interval);
+        }
+        for client in self.clients.drain(..) {
+            self.shared.clients.remove(client.id);
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+        for client in self.pending.drain(..) {
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+        self.logs.flush();
+        self.with_recorder(|recorder, _| recorder.close());
+    }
+    fn notify_dropped(&mut self) {
+        if self.last_drop_notice.elapsed() < DROP_NOTICE_INTERVAL {
+            return;
+        }
+        self.last_drop_notice = Instant::now();
+        let dropped = self.shared.dropped.get();
+        let notice = dropped.since(&self.reported_dropped);
+        if notice.total() == 0 {
+            return;
+        }
+        self.reported_dropped = dropped;
+        let _ = self.for_each_client(|_, client| Ok(client.send(&ToClient::Dropped(notice))?));
+    }
+    // stops recording if the recorder errors, e.g. the disk is full
+    fn with_recorder(
+        &mut self,
+        f: impl FnOnce(&mut Recorder, &PlotSettings) -> Result<(), packet::Error>,
+    ) {
+        let Some(recorder) = &mut self.recorder else {
+            return;
+        };
+        if let Err(e) = f(recorder, &self.plot_settings) {
+            self.recorder = None;
+            log::error!("Failed to record to disk, no longer recording: {e}");
+        }
+    }
+    // the handshake is never waited on, a client that is slow to send its
+    // Hello is kept in pending for up to packet::HANDSHAKE_TIMEOUT while
+    // everyone else is served
+    fn accept_clients(&mut self) -> Result<(), Error> {
+        loop {
+            let (mut stream, addr) = match self.tcp.accept() {
+                Ok(s) => s,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    log::warn!("Failed to accept client: {e}");
+                    break;
                 }
-                Ok(()) if !s.was_connected => {}
-                _ => {}
+            };
+
+            // logs and plots are small and sent as they happen, see
+            // Client::from_stream
+            let res = stream
+                .set_nodelay(true)
+                .and_then(|()| stream.set_nonblocking(true))
+                .map_err(packet::Error::from)
+                .and_then(|()| packet::send(&mut stream, &Hello::new(Capabilities::ALL)));
+            if let Err(e) = res {
+                log::warn!("Refused client {addr}: {e}");
+                continue;
             }
+            self.pending.push(PendingClient {
+                stream,
+                decoder: FrameDecoder::new(packet::MAX_HELLO_LEN),
+                addr,
+                accepted: Instant::now(),
+            });
         }
+        self.poll_pending()
+    }
+    // connects every pending client whose Hello has arrived
+    fn poll_pending(&mut self) -> Result<(), Error> {
+        let mut i = 0;
+        while i < self.pending.len() {
+            let client = &mut self.pending[i];
+            let res =
+                packet::poll_hello(&mut client.stream, &mut client.decoder, Capabilities::ALL);
+            let capabilities = match res {
+                Ok(None) if client.accepted.elapsed() < packet::HANDSHAKE_TIMEOUT => {
+                    i += 1;
+                    continue;
+                }
+                Ok(None) => {
+                    log::warn!("Refused client {}: no Hello received", client.addr);
+                    self.pending.swap_remove(i);
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("Refused client {}: {e}", client.addr);
+                    self.pending.swap_remove(i);
+                    continue;
+                }
+                Ok(Some(capabilities)) => capabilities,
+            };
+            let client = self.pending.swap_remove(i);
+            self.connect(client, capabilities)?;
+        }
+        Ok(())
+    }
+    fn connect(&mut self, client: PendingClient, capabilities: Capabilities) -> Result<(), Error> {
+        let PendingClient {
+            stream,
+            mut decoder,
+            addr,
+            ..
+        } = client;
+        decoder.set_max_frame_len(self.max_frame_len);
 
-        log::error!(
-            "listener thread has exited. The application can no longer communicate with clients.\n\
-             This message should be unreachable as the sender should never be dropped."
-        );
+        log::info!("Client {addr} connected.");
+        let id = ClientId(self.next_client_id);
+        self.next_client_id += 1;
+        self.clients.push(Connection {
+            id,
+            stream,
+            decoder,
+            addr,
+            capabilities,
+            outgoing: Vec::new(),
+            next_log: 0,
+            log_filter: LogFilter::default(),
+            plot_manager: PlotManager::default(),
+            last_received: Instant::now(),
+            last_heartbeat: Instant::now(),
+            subscriptions: HashSet::new(),
+        });
+        let info = ClientInfo {
+            id,
+            addr,
+            capabilities,
+        };
+        self.shared.clients.insert(info);
+        self.to_mediator(ToMediator::ClientConnected(info))?;
         Ok(())
     }
+    // waits up to poll_interval for a packet then handles everything queued
     fn read_from_mediator(&mut self) -> Result<(), Error> {
-        let mut stream = self.tcp.accept()?.0;
-        stream.set_nonblocking(true)?;
+        let from_mediator = crossbeam_channel::select! {
+            recv(self.rx) -> pkt => pkt?,
+            recv(self.telemetry) -> pkt => match pkt {
+                Ok(pkt) => pkt,
+                // every sink has been dropped, there is still the mediator
+                Err(RecvError) => {
+                    self.telemetry = crossbeam_channel::never();
+                    return Ok(());
+                }
+            },
+            default(self.poll_interval) => return Ok(()),
+        };
+        self.process_packet(from_mediator)?;
 
-        if !self.was_connected {
-            log::info!("Client connected.");
+        for _ in 1..MAX_PACKETS_PER_POLL {
+            if self.stopped {
+                break;
+            }
+            match self.try_recv() {
+                Some(pkt) => self.process_packet(pkt)?,
+                None => break,
+            }
         }
-        self.was_connected = true;
-
-        loop {
-            let from_mediator = self.rx.recv()?;
-
-            self.packet_buffer.push_back(from_mediator);
-
-            while let Some(pkt) = self.packet_buffer.pop_front() {
-                self.process_packet(&mut stream, pkt)?;
+        Ok(())
+    }
+    // packets from the mediator come first so that e.g. a response isn't
+    // held up by a flood of logs
+    fn try_recv(&self) -> Option<FromMediator> {
+        self.rx
+            .try_recv()
+            .or_else(|_| self.telemetry.try_recv())
+            .ok()
+    }
+    // runs f on every client, disconnecting any client that errors. Errors
+    // communicating with the main thread are returned instead
+    fn for_each_client(
+        &mut self,
+        mut f: impl FnMut(&mut Self, &mut Connection) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut clients = std::mem::take(&mut self.clients);
+        let mut result = Ok(());
+        let mut disconnected = Vec::new();
+        clients.retain_mut(|client| match f(self, client) {
+            Ok(()) => true,
+            Err(e @ (Error::Recv(_) | Error::Send(_))) => {
+                result = Err(e);
+                true
             }
-
-            self.process_plot_points(&mut stream)?;
+            Err(e) => {
+                log::warn!("Client {} disconnected: {e}", client.addr);
+                // fails if the client hung up, but not if it is being
+                // disconnected for e.g. a heartbeat timeout
+                let _ = client.flush_plots().and_then(|()| client.write_outgoing());
+                disconnected.push((client.info(), e.to_string()));
+                false
+            }
+        });
+        self.clients = clients;
+        for (client, reason) in disconnected {
+            self.shared.clients.remove(client.id);
+            self.to_mediator(ToMediator::ClientDisconnected { client, reason })?;
         }
+        result
     }
-    fn process_plot_points(&mut self, stream: &mut TcpStream) -> Result<(), Error> {
-        for buffer in self.plot_manager.buffers_to_send() {
-            packet::send(stream, &ToClient::PointBuffer(buffer))?;
+    fn process_plot_points(&mut self, client: &mut Connection) -> Result<(), Error> {
+        for buffer in client.plot_manager.buffers_to_send(&self.plot_settings) {
+            client.send(&ToClient::PointBuffer(buffer))?;
         }
         Ok(())
     }
-    fn process_packet(&mut self, stream: &mut TcpStream, pkt: FromMediator) -> Result<(), Error> {
+    // queues any logs held back by send_logs then writes as much of the
+    // client's queue as its socket takes
+    fn write_outgoing(&mut self, client: &mut Connection) -> Result<(), Error> {
+        self.send_logs(client)?;
+        client.write_outgoing()?;
+        Ok(())
+    }
+    fn process_packet(&mut self, pkt: FromMediator) -> Result<(), Error> {
+        self.with_recorder(|recorder, _| recorder.record(&pkt));
         match pkt {
             FromMediator::Log(log) => {
-                self.logs.push(ToClient::Log(log));
-                self.send_logs(stream)?;
-            }
-            FromMediator::Pong => packet::send(stream, &ToClient::Pong)?,
-            FromMediator::Path(p) => packet::send(stream, &ToClient::Path(p))?,
-            FromMediator::PollEvents => self.poll_tcp_events(stream)?,
-            FromMediator::Point(p) => self.plot_manager.add_point(p),
-            FromMediator::Odometry((pos, heading)) => packet::send(
-                stream,
-                &ToClient::Odometry((
-                    FIRST_ROBOT.load(std::sync::atomic::Ordering::Relaxed),
-                    pos,
-                    heading,
-                )),
-            )?,
+                self.logs.push(ToClient::Log(*log));
+                self.for_each_client(|s, client| Ok(s.send_logs(client)?))
+            }
+            FromMediator::ExtendedLog(log) => {
+                self.logs.push(ToClient::ExtendedLog(*log));
+                self.for_each_client(|s, client| Ok(s.send_logs(client)?))
+            }
+            FromMediator::PlotSettings((plot, settings)) => {
+                self.plot_settings.set(plot, settings);
+                Ok(())
+            }
+            FromMediator::Shutdown => {
+                self.stopped = true;
+                Ok(())
+            }
+            FromMediator::Pong(ping) => self.send_to(
+                ping.client,
+                &ToClient::Pong {
+                    seq: ping.seq,
+                    source: PongSource::Mediator,
+                },
+            ),
+            FromMediator::Response { client, id, result } => {
+                self.send_to(client, &ToClient::Response { id, result })
+            }
+            pkt => self.for_each_client(|s, client| client.process_packet(&pkt, s.first_robot)),
+        }
+    }
+    // sends the subscribers of every parameter changed by clients or robot
+    // code since the last call its current value. Changes made by clients
+    // are only sent once every client has been read as all but the current
+    // client are out of reach while reading one
+    fn notify_changed_params(&mut self) -> Result<(), Error> {
+        for (name, value) in self.shared.params.take_changed() {
+            let pkt = ToClient::ParamChanged {
+                name: name.clone(),
+                value,
+            };
+            self.for_each_client(|_, client| {
+                if client.subscriptions.contains(&name) {
+                    client.send(&pkt)?;
+                }
+                Ok(())
+            })?;
         }
         Ok(())
     }
-    // sends logs if needed to stream and update log index
-    fn send_logs(&mut self, stream: &mut TcpStream) -> Result<(), packet::Error> {
-        let unsent = &self.logs[self.last_log..];
-        for log in unsent {
-            packet::send(stream, log)?;
-            self.last_log += 1;
+    // dropped if the client has since disconnected
+    fn send_to(&mut self, id: ClientId, pkt: &ToClient) -> Result<(), Error> {
+        self.for_each_client(|_, client| {
+            if client.id == id {
+                client.send(pkt)?;
+            }
+            Ok(())
+        })
+    }
+    // queues the logs the client hasn't been sent yet and updates its log
+    // index. Stops once MAX_QUEUED_LOGS_LEN bytes are queued, the rest are
+    // sent once the client has read some of them, see write_outgoing
+    fn send_logs(&mut self, client: &mut Connection) -> Result<(), packet::Error> {
+        if !client.capabilities.contains(Capabilities::LOGS) {
+            return Ok(());
+        }
+        self.logs.evict();
+        if client.next_log < self.logs.first() {
+            let dropped = self.logs.first() - client.next_log;
+            client.send(&ToClient::LogsDropped(dropped))?;
+            client.next_log = self.logs.first();
+        }
+        let extended = client.capabilities.contains(Capabilities::EXTENDED_LOGS);
+        for log in self.logs.since(client.next_log) {
+            if client.outgoing.len() >= MAX_QUEUED_LOGS_LEN {
+                break;
+            }
+            match log {
+                ToClient::Log(log) if !client.log_filter.matches(log) => {}
+                ToClient::ExtendedLog(log) if !client.log_filter.matches(&log.log) => {}
+                // older clients still get the message
+                ToClient::ExtendedLog(log) if !extended => {
+                    client.send(&ToClient::Log(log.log.clone()))?
+                }
+                log => client.send(log)?,
+            }
+            client.next_log += 1;
         }
         Ok(())
     }
-    fn poll_tcp_events(&mut self, stream: &mut TcpStream) -> Result<(), Error> {
-        let mut pkt_fn = |stream: &mut _, pkt| -> Result<(), Error> {
+    // whether everything queued for the client has been written
+    fn caught_up(&self, client: &Connection) -> bool {
+        let logs_sent = !client.capabilities.contains(Capabilities::LOGS)
+            || (client.next_log >= self.logs.first()
+                && self.logs.since(client.next_log).next().is_none());
+        logs_sent && client.outgoing.is_empty()
+    }
+    // a main loop that isn't polling the mediator mustn't stall the
+    // listener, so events are discarded and counted once the channel is
+    // full. Returns whether the event was queued
+    fn to_mediator(&self, event: ToMediator) -> Result<bool, Error> {
+        match self.tx.try_send(event) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full(_)) => {
+                self.shared.dropped.count_event();
+                Ok(false)
+            }
+            Err(TrySendError::Disconnected(event)) => Err(SendError(event))?,
+        }
+    }
+    // checked after reading so a client is never timed out while its
+    // packets are waiting to be read
+    fn check_heartbeat(&self, client: &mut Connection, received: bool) -> Result<(), Error> {
+        if received {
+            client.last_received = Instant::now();
+        } else if client.last_received.elapsed() > self.heartbeat.timeout {
+            Err(packet::Error::HeartbeatTimeout(
+                client.last_received.elapsed(),
+            ))?;
+        }
+        if client.last_heartbeat.elapsed() >= self.heartbeat.interval {
+            client.send(&ToClient::Heartbeat)?;
+            client.last_heartbeat = Instant::now();
+        }
+        Ok(())
+    }
+    fn poll_tcp_events(&mut self, client: &mut Connection) -> Result<(), Error> {
+        let mut requested_logs = false;
+        let mut replies = Vec::new();
+        let mut received = false;
+        let mut pkt_fn = |_: &mut _, pkt| -> Result<(), Error> {
+            received = true;
             match pkt {
-                ToRobot::Ping => self.tx.send(ToMediator::Ping)?,
-                ToRobot::Path(p) => self.tx.send(ToMediator::Path(p))?,
-                ToRobot::RequestLogs => {
-                    self.send_logs(stream)?;
+                ToRobot::Heartbeat => {}
+                ToRobot::Ping(seq) => {
+                    replies.push(ToClient::Pong {
+                        seq,
+                        source: PongSource::Listener,
+                    });
+                    self.to_mediator(ToMediator::Ping(Ping {
+                        client: client.id,
+                        seq,
+                    }))?;
+                }
+                ToRobot::Path(p) => {
+                    self.to_mediator(ToMediator::Path(p))?;
+                }
+                // the stream is borrowed by recieve_multiple so the logs
+                // are sent once all packets have been read
+                ToRobot::RequestLogs => requested_logs = true,
+                ToRobot::Pid(p) => {
+                    self.to_mediator(ToMediator::Pid(p))?;
+                }
+                ToRobot::Request {
+                    id,
+                    method,
+                    payload,
+                } => {
+                    let request = ToMediator::Request(rpc::Request {
+                        client: client.id,
+                        id,
+                        method,
+                        payload,
+                    });
+                    // answered now rather than leaving the client to time out
+                    if !self.to_mediator(request)? {
+                        replies.push(ToClient::Response {
+                            id,
+                            result: Err(String::from("the robot is too busy to answer")),
+                        });
+                    }
+                }
+                ToRobot::SetLogLevel(level) => client.log_filter.level = level,
+                ToRobot::SetLogTargets(targets) => client.log_filter.targets = targets,
+                ToRobot::SetLogFilter(spec) => {
+                    let reply = self
+                        .shared
+                        .filter
+                        .set(&spec)
+                        .map(|()| self.shared.filter.spec());
+                    match &reply {
+                        Ok(spec) => {
+                            log::info!("Client {} set the log filter to {spec}", client.addr)
+                        }
+                        Err(e) => {
+                            log::warn!("Client {} sent an invalid log filter: {e}", client.addr)
+                        }
+                    }
+                    replies.push(ToClient::LogFilter(reply));
+                }
+                ToRobot::GetPid(name) => {
+                    let result = self.shared.pids.get(&name);
+                    replies.push(ToClient::Pid { name, result });
+                }
+                ToRobot::SetPid((name, gains)) => {
+                    let result = self.shared.pids.set(&name, gains);
+                    match &result {
+                        Ok(gains) => {
+                            log::info!("Client {} set PID {name} to {gains:?}", client.addr);
+                            // Mediator::pid has the gains even if this is
+                            // dropped
+                            self.to_mediator(ToMediator::PidChanged {
+                                name: name.clone(),
+                                gains: *gains,
+                            })?;
+                        }
+                        Err(e) => {
+                            log::warn!("Client {} failed to set PID {name}: {e}", client.addr)
+                        }
+                    }
+                    replies.push(ToClient::Pid { name, result });
+                }
+                ToRobot::ListPids => replies.push(ToClient::Pids(self.shared.pids.list())),
+                ToRobot::ListParams => replies.push(ToClient::Params(self.shared.params.list())),
+                ToRobot::GetParam(name) => {
+                    let result = self.shared.params.get(&name);
+                    replies.push(ToClient::Param { name, result });
+                }
+                ToRobot::SetParam((name, value)) => {
+                    let result = self.shared.params.set(&name, value);
+                    match &result {
+                        Ok(value) => {
+                            log::info!("Client {} set {name} to {value:?}", client.addr);
+                            // Mediator::param has the value even if this is
+                            // dropped
+                            self.to_mediator(ToMediator::ParamChanged {
+                                name: name.clone(),
+                                value: value.clone(),
+                            })?;
+                        }
+                        Err(e) => log::warn!("Client {} failed to set {name}: {e}", client.addr),
+                    }
+                    replies.push(ToClient::Param { name, result });
+                }
+                ToRobot::SubscribeParams(names) => {
+                    client.subscriptions = names.into_iter().collect();
+                    for name in &client.subscriptions {
+                        if let Ok(value) = self.shared.params.get(name) {
+                            replies.push(ToClient::ParamChanged {
+                                name: name.clone(),
+                                value,
+                            });
+                        }
+                    }
                 }
-                ToRobot::Pid(p) => self.tx.send(ToMediator::Pid(p))?,
             }
             Ok(())
         };
-        packet::recieve_multiple(stream, &mut pkt_fn)
+        packet::recieve_multiple(&mut client.stream, &mut client.decoder, &mut pkt_fn)?;
+        self.check_heartbeat(client, received)?;
+        for reply in replies {
+            client.send(&reply)?;
+        }
+        if requested_logs {
+            self.send_logs(client)?;
+        }
+        Ok(())
+    }
+}
+
+// the connected clients, shared with the mediator so that it knows who is
+// connected even if it misses ToMediator::ClientConnected or
+// ClientDisconnected because it wasn't polling
+#[derive(Debug, Default)]
+pub(crate) struct ClientRegistry {
+    clients: RwLock<BTreeMap<ClientId, ClientInfo>>,
+}
+
+impl ClientRegistry {
+    fn insert(&self, client: ClientInfo) {
+        self.write().insert(client.id, client);
+    }
+
+    fn remove(&self, id: ClientId) {
+        self.write().remove(&id);
+    }
+
+    pub(crate) fn list(&self) -> Vec<ClientInfo> {
+        self.read().values().copied().collect()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<ClientId, ClientInfo>> {
+        self.clients.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<ClientId, ClientInfo>> {
+        self.clients.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Connection {
+    fn info(&self) -> ClientInfo {
+        ClientInfo {
+            id: self.id,
+            addr: self.addr,
+            capabilities: self.capabilities,
+        }
+    }
+    // queues pkt to be written by write_outgoing, the socket is nonblocking
+    // so writing straight to it could leave half a frame behind
+    fn send(&mut self, pkt: &ToClient) -> Result<(), packet::Error> {
+        packet::send(&mut self.outgoing, pkt)?;
+        if self.outgoing.len() > MAX_OUTGOING_LEN {
+            return Err(packet::Error::Backlog(self.outgoing.len()));
+        }
+        Ok(())
+    }
+    // writes until the queue is empty or the socket would block
+    fn write_outgoing(&mut self) -> Result<(), packet::Error> {
+        let mut written = 0;
+        let res = loop {
+            if written == self.outgoing.len() {
+                break Ok(());
+            }
+            match self.stream.write(&self.outgoing[written..]) {
+                Ok(0) => break Err(std::io::Error::from(std::io::ErrorKind::WriteZero)),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.outgoing.drain(..written);
+        Ok(res?)
+    }
+    // every buffered point regardless of the plot's BufferSettings
+    fn flush_plots(&mut self) -> Result<(), packet::Error> {
+        for buffer in self.plot_manager.flush_all() {
+            self.send(&ToClient::PointBuffer(buffer))?;
+        }
+        Ok(())
+    }
+    // handles packets that are forwarded to every client unchanged
+    fn process_packet(&mut self, pkt: &FromMediator, first_robot: bool) -> Result<(), Error> {
+        match pkt {
+            // the client doesn't understand these so silently drop them
+            FromMediator::Path(_) if !self.capabilities.contains(Capabilities::PATHS) => {}
+            FromMediator::Point(_) if !self.capabilities.contains(Capabilities::PLOTS) => {}
+            FromMediator::Odometry(_) if !self.capabilities.contains(Capabilities::ODOMETRY) => {}
+            FromMediator::Path(p) => self.send(&ToClient::Path(p.clone()))?,
+            FromMediator::Point(p) => self.plot_manager.add_point(p.clone()),
+            FromMediator::Odometry((pos, heading)) => {
+                self.send(&ToClient::Odometry((first_robot, *pos, *heading)))?
+            }
+            FromMediator::Log(_)
+            | FromMediator::ExtendedLog(_)
+            | FromMediator::PlotSettings(_)
+            | FromMediator::Pong(_)
+            | FromMediator::Response { .. }
+            | FromMediator::Shutdown => {
+                unreachable!("handled by Listener::process_packet")
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::{Client, ConnectionState},
+        params::{Param, ParamValue},
+        pid::Gains,
+        plot::Point,
+        tests::spawn_listener,
+        LoggerConfig, SimpleLog,
+    };
+    use std::io::{Read, Write};
+    use std::net::Ipv4Addr;
+    use std::time::Instant;
+
+    // a free port on localhost, see Mediator::local_addr
+    fn config() -> LoggerConfig {
+        LoggerConfig::default()
+            .bind_address(Ipv4Addr::LOCALHOST)
+            .port(0)
+    }
+
+    #[test]
+    fn custom_address() {
+        let (mut mediator, _) = spawn_listener(config());
+
+        let log = SimpleLog {
+            level: log::Level::Info,
+            msg: String::from("test"),
+            target: String::from("test"),
+            timestamp: std::time::SystemTime::now(),
+            module_path: None,
+            file: None,
+            line: None,
+            fields: Default::default(),
+        };
+        mediator
+            .send_event(FromMediator::Log(Box::new(log.clone())))
+            .unwrap();
+
+        let mut client = Client::new(mediator.local_addr()).unwrap();
+        // wait for the listener to read RequestLogs
+        std::thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(client.receive_data().unwrap(), vec![ToClient::Log(log)]);
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn odometry_from_second_robot() {
+        let (mut mediator, _) = spawn_listener(config().first_robot(false));
+
+        let mut client = Client::new(mediator.local_addr()).unwrap();
+        // wait for the listener to accept the client
+        std::thread::sleep(Duration::from_millis(20));
+        mediator
+            .send_event(FromMediator::Odometry(([1.0, 2.0], 3.0)))
+            .unwrap();
+        mediator.shutdown().unwrap();
+
+        assert_eq!(
+            client.receive_data().unwrap(),
+            [ToClient::Odometry((false, [1.0, 2.0], 3.0))]
+        );
+    }
+
+    #[test]
+    fn serves_without_disk() {
+        // a file can't contain anything so neither of these can be created
+        let file = std::env::current_exe().unwrap();
+        let config = config()
+            .spill_logs_to(file.join("spill.bin"))
+            .record_to(file.join("recordings"));
+        let (mediator, _) = spawn_listener(config);
+
+        let mut client = Client::new(mediator.local_addr()).unwrap();
+        client.ping().unwrap();
+        let start = Instant::now();
+        while client.rtt(PongSource::Listener).is_none() {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            client.receive_data().unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn slow_handshake_doesnt_block() {
+        let (mediator, _) = spawn_listener(config());
+
+        // never sends its Hello
+        let mut stalled = TcpStream::connect(mediator.local_addr()).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        let start = Instant::now();
+        let mut client = Client::new(mediator.local_addr()).unwrap();
+        client.ping().unwrap();
+        while client.rtt(PongSource::Listener).is_none() {
+            assert!(start.elapsed() < packet::HANDSHAKE_TIMEOUT / 2);
+            client.receive_data().unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        assert_eq!(mediator.clients().len(), 1);
+
+        // hung up on once the handshake times out
+        stalled
+            .set_read_timeout(Some(packet::HANDSHAKE_TIMEOUT * 2))
+            .unwrap();
+        stalled.read_to_end(&mut Vec::new()).unwrap();
+        assert!(start.elapsed() >= packet::HANDSHAKE_TIMEOUT / 2);
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn oversized_frame_disconnects() {
+        let (mediator, _) = spawn_listener(config().max_frame_len(64));
+
+        let mut stream = TcpStream::connect(mediator.local_addr()).unwrap();
+        packet::handshake(&mut stream, Capabilities::ALL).unwrap();
+
+        // a hostile length prefix, the listener should hang up rather than
+        // try to allocate 4GiB
+        stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+
+        stream.set_nonblocking(false).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(stream.read(&mut [0u8; 16]).unwrap(), 0);
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn shutdown_flushes_and_disconnects() {
+        let (mut mediator, _) = spawn_listener(config());
+
+        let mut client = Client::new(mediator.local_addr()).unwrap();
+        // wait for the listener to accept the client
+        std::thread::sleep(Duration::from_millis(20));
+
+        // a single point won't fill a buffer or time out so is only sent
+        // because of the shutdown
+        mediator
+            .send_event(FromMediator::Point((
+                (String::from("plot"), String::from("plot")),
+                Point::Scalar((Instant::now(), 1.0)),
+            )))
+            .unwrap();
+        mediator.shutdown().unwrap();
+
+        let pkts = client.receive_data().unwrap();
+        assert!(matches!(pkts.as_slice(), [ToClient::PointBuffer(_)]));
+        assert!(client.receive_data().is_err());
+    }
+
+    #[test]
+    fn slow_client_catches_up() {
+        let (mut mediator, _) = spawn_listener(config());
+
+        let mut client = Client::new(mediator.local_addr()).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        // far more than the socket buffers hold while the client isn't
+        // reading
+        let count = 4000;
+        for i in 0..count {
+            let log = SimpleLog {
+                level: log::Level::Info,
+                msg: format!("{i:01000}"),
+                target: String::from("test"),
+                timestamp: std::time::SystemTime::now(),
+                module_path: None,
+                file: None,
+                line: None,
+                fields: Default::default(),
+            };
+            mediator
+                .send_event(FromMediator::Log(Box::new(log)))
+                .unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(200));
+
+        let mut received = Vec::new();
+        let start = Instant::now();
+        while received.len() < count && start.elapsed() < Duration::from_secs(5) {
+            for pkt in client.receive_data().unwrap() {
+                match pkt {
+                    ToClient::Log(log) => received.push(log.msg.parse::<usize>().unwrap()),
+                    pkt => panic!("unexpected packet {pkt:?}"),
+                }
+            }
+        }
+        assert_eq!(received, (0..count).collect::<Vec<_>>());
+        assert!(matches!(client.state(), ConnectionState::Connected));
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn client_log_filter() {
+        let (mut mediator, _) = spawn_listener(config());
+
+        let mut client = Client::new(mediator.local_addr()).unwrap();
+        client
+            .send_request(&ToRobot::SetLogLevel(LevelFilter::Warn))
+            .unwrap();
+        client
+            .send_request(&ToRobot::SetLogTargets(vec![String::from("drive")]))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        let logs = [
+            (log::Level::Info, "drive"),
+            (log::Level::Warn, "drive::motors"),
+            (log::Level::Error, "intake"),
+            (log::Level::Error, "drivetrain"),
+            (log::Level::Error, "drive"),
+        ];
+        for (level, target) in logs {
+            let log = SimpleLog {
+                level,
+                msg: String::from("test"),
+                target: String::from(target),
+                timestamp: std::time::SystemTime::now(),
+                module_path: None,
+                file: None,
+                line: None,
+                fields: Default::default(),
+            };
+            mediator
+                .send_event(FromMediator::Log(Box::new(log)))
+                .unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(50));
+
+        let received: Vec<_> = client
+            .receive_data()
+            .unwrap()
+            .into_iter()
+            .map(|pkt| match pkt {
+                ToClient::Log(log) => (log.level, log.target),
+                pkt => panic!("unexpected packet {pkt:?}"),
+            })
+            .collect();
+        assert_eq!(
+            received,
+            [
+                (log::Level::Warn, String::from("drive::motors")),
+                (log::Level::Error, String::from("drive"))
+            ]
+        );
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn set_log_filter() {
+        let (mediator, shared) = spawn_listener(config());
+
+        let mut client = Client::new(mediator.local_addr()).unwrap();
+        client
+            .send_request(&ToRobot::SetLogFilter(String::from("warn,drive=debug")))
+            .unwrap();
+        client
+            .send_request(&ToRobot::SetLogFilter(String::from("drive=loud")))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let pkts = client.receive_data().unwrap();
+        assert!(matches!(
+            pkts.as_slice(),
+            [ToClient::LogFilter(Ok(spec)), ToClient::LogFilter(Err(_))] if spec == "warn,drive=debug"
+        ));
+        assert_eq!(shared.filter.spec(), "warn,drive=debug");
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn tunes_pids() {
+        let (mut mediator, _) = spawn_listener(config());
+        let drive = Gains::new(1.0, 0.0, 0.0);
+        mediator.register_pid("drive", drive);
+
+        let mut client = Client::new(mediator.local_addr()).unwrap();
+        let tuned = drive.kf(0.2).output_limits(-12.0, 12.0);
+        client
+            .send_request(&ToRobot::SetPid((String::from("drive"), tuned)))
+            .unwrap();
+        client
+            .send_request(&ToRobot::SetPid((String::from("lift"), tuned)))
+            .unwrap();
+        client
+            .send_request(&ToRobot::GetPid(String::from("drive")))
+            .unwrap();
+        client.send_request(&ToRobot::ListPids).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let pkts: Vec<_> = client
+            .receive_data()
+            .unwrap()
+            .into_iter()
+            .filter(|pkt| !matches!(pkt, ToClient::Log(_)))
+            .collect();
+        assert_eq!(
+            pkts,
+            [
+                ToClient::Pid {
+                    name: String::from("drive"),
+                    result: Ok(tuned)
+                },
+                ToClient::Pid {
+                    name: String::from("lift"),
+                    result: Err(String::from("no PID controller named lift"))
+                },
+                ToClient::Pid {
+                    name: String::from("drive"),
+                    result: Ok(tuned)
+                },
+                ToClient::Pids(vec![(String::from("drive"), tuned)]),
+            ]
+        );
+        assert_eq!(mediator.pid("drive"), Some(tuned));
+        assert!(mediator.poll_events().unwrap().iter().any(|event| matches!(
+            event,
+            ToMediator::PidChanged { name, gains } if name == "drive" && *gains == tuned
+        )));
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn subscribes_to_params() {
+        let (mut mediator, _) = spawn_listener(config());
+        mediator
+            .declare_param("speed", Param::new(0.5).range(0.0, 1.0))
+            .unwrap();
+        mediator.declare_param("auton", Param::new("left")).unwrap();
+
+        let changed = |name: &str, value: ParamValue| ToClient::ParamChanged {
+            name: String::from(name),
+            value,
+        };
+        let receive = |client: &mut Client| -> Vec<ToClient> {
+            std::thread::sleep(Duration::from_millis(50));
+            client
+                .receive_data()
+                .unwrap()
+                .into_iter()
+                .filter(|pkt| !matches!(pkt, ToClient::Log(_)))
+                .collect()
+        };
+
+        let mut subscriber = Client::new(mediator.local_addr()).unwrap();
+        subscriber
+            .send_request(&ToRobot::SubscribeParams(vec![String::from("speed")]))
+            .unwrap();
+        assert_eq!(receive(&mut subscriber), [changed("speed", 0.5.into())]);
+
+        // changes by another client and by robot code are both sent
+        let mut other = Client::new(mediator.local_addr()).unwrap();
+        other
+            .send_request(&ToRobot::SetParam((String::from("speed"), 0.8.into())))
+            .unwrap();
+        other
+            .send_request(&ToRobot::SetParam((String::from("speed"), 2.0.into())))
+            .unwrap();
+        other
+            .send_request(&ToRobot::SetParam((String::from("auton"), "right".into())))
+            .unwrap();
+        let replies = receive(&mut other);
+        assert!(matches!(
+            replies.as_slice(),
+            [
+                ToClient::Param { result: Ok(_), .. },
+                ToClient::Param { result: Err(_), .. },
+                ToClient::Param { result: Ok(_), .. },
+            ]
+        ));
+        assert_eq!(receive(&mut subscriber), [changed("speed", 0.8.into())]);
+        assert_eq!(mediator.param::<f64>("speed"), Some(0.8));
+        assert_eq!(mediator.param::<String>("auton").as_deref(), Some("right"));
+        let events = mediator.poll_events().unwrap();
+        let param_events: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                ToMediator::ParamChanged { name, .. } => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(param_events, ["speed", "auton"]);
+
+        mediator.set_param("speed", 0.1).unwrap();
+        assert!(mediator.set_param("speed", -1.0).is_err());
+        assert_eq!(receive(&mut subscriber), [changed("speed", 0.1.into())]);
+
+        other.send_request(&ToRobot::ListParams).unwrap();
+        assert!(matches!(
+            receive(&mut other).as_slice(),
+            [ToClient::Params(params)] if params.len() == 2
+        ));
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn reads_clients_without_mediator() {
+        let (mut mediator, _) = spawn_listener(config());
+
+        // nothing is sent to the listener, it should still read the ping
+        let mut client = Client::new(mediator.local_addr()).unwrap();
+        client.send_request(&ToRobot::Ping(3)).unwrap();
+        let start = Instant::now();
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            events.extend(mediator.poll_events().unwrap());
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        assert!(matches!(
+            events.as_slice(),
+            [
+                ToMediator::ClientConnected(_),
+                ToMediator::Ping(Ping { seq: 3, .. })
+            ]
+        ));
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn poll_events_reports_exit() {
+        let (mut mediator, _) = spawn_listener(config());
+        // stops the listener without consuming the mediator
+        mediator.send_event(FromMediator::Shutdown).unwrap();
+        let start = Instant::now();
+        while mediator.poll_events().is_ok() {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        assert!(matches!(
+            mediator.poll_events(),
+            Err(crate::mediator::Error::ListenerExited)
+        ));
+    }
+
+    #[test]
+    fn pongs_only_the_pinging_client() {
+        let (mut mediator, _) = spawn_listener(config());
+
+        let mut pinging = Client::new(mediator.local_addr()).unwrap();
+        let mut other = Client::new(mediator.local_addr()).unwrap();
+        pinging.ping().unwrap();
+        let start = Instant::now();
+        while pinging.rtt(PongSource::Mediator).is_none() {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            for event in mediator.poll_events().unwrap() {
+                if let ToMediator::Ping(ping) = event {
+                    mediator.send_event(FromMediator::Pong(ping)).unwrap();
+                }
+            }
+            pinging.receive_data().unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        assert!(!other
+            .receive_data()
+            .unwrap()
+            .iter()
+            .any(|pkt| matches!(pkt, ToClient::Pong { .. })));
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn drops_events_when_mediator_is_full() {
+        struct Add;
+        impl rpc::Rpc for Add {
+            const METHOD: &'static str = "add";
+            type Request = (i32, i32);
+            type Response = i32;
+        }
+
+        // only room for ClientConnected, the main loop never polls
+        let (mediator, shared) = spawn_listener(config().buffer_size(1));
+
+        let mut client = Client::new(mediator.local_addr()).unwrap();
+        for _ in 0..4 {
+            client.send_request(&ToRobot::Path(Vec::new())).unwrap();
+        }
+        assert!(matches!(
+            client.call::<Add>(&(1, 2), Duration::from_secs(1)),
+            Err(rpc::Error::Remote(_))
+        ));
+        assert_eq!(shared.dropped.get().events, 5);
+
+        // dropped connection events don't stop the mediator knowing who is
+        // connected
+        let other = Client::new(mediator.local_addr()).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(mediator.clients().len(), 2);
+        drop(other);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(mediator.clients().len(), 1);
+        assert_eq!(shared.dropped.get().events, 7);
+
+        mediator.shutdown().unwrap();
+    }
+
+    #[test]
+    fn times_out_silent_clients() {
+        let (mut mediator, _) = spawn_listener(config().heartbeat(Heartbeat {
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(100),
+        }));
+
+        assert_eq!(mediator.connection_state(), ConnectionState::Disconnected);
+        // a raw connection that never sends heartbeats
+        let mut stream = TcpStream::connect(mediator.local_addr()).unwrap();
+        packet::handshake(&mut stream, Capabilities::NONE).unwrap();
+
+        let start = Instant::now();
+        let connected = loop {
+            let events = mediator.poll_events().unwrap();
+            if let Some(ToMediator::ClientConnected(info)) = events.first() {
+                break *info;
+            }
+            assert!(start.elapsed() < Duration::from_secs(2));
+            std::thread::sleep(Duration::from_millis(2));
+        };
+        assert_eq!(mediator.connection_state(), ConnectionState::Connected);
+        let info = mediator.clients()[0];
+        assert_eq!(info, connected);
+        assert_eq!(info.addr, stream.local_addr().unwrap());
+        assert_eq!(info.capabilities, Capabilities::NONE);
+
+        let reason = loop {
+            assert!(start.elapsed() < Duration::from_secs(2));
+            let disconnected = mediator
+                .poll_events()
+                .unwrap()
+                .into_iter()
+                .find_map(|event| match event {
+                    ToMediator::ClientDisconnected { client, reason } if client == info => {
+                        Some(reason)
+                    }
+                    _ => None,
+                });
+            if let Some(reason) = disconnected {
+                break reason;
+            }
+            std::thread::sleep(Duration::from_millis(2));
+        };
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(reason.contains("nothing received"), "{reason}");
+        assert_eq!(mediator.connection_state(), ConnectionState::Disconnected);
+
+        // heartbeats were sent until the listener hung up
+        stream.set_nonblocking(false).unwrap();
+        let mut decoder = FrameDecoder::default();
+        let mut heartbeats = 0;
+        let res = packet::recieve_multiple(&mut stream, &mut decoder, &mut |_, pkt: ToClient| {
+            assert_eq!(pkt, ToClient::Heartbeat);
+            heartbeats += 1;
+            Ok::<_, packet::Error>(())
+        });
+        assert!(res.is_err());
+        assert!(heartbeats > 1);
+
+        mediator.shutdown().unwrap();
     }
 }
@@ -0,0 +1,89 @@
+use log::{LevelFilter, Metadata, Record};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    RwLock,
+};
+
+// the robot's env_logger style filter, shared by the Logger (for both
+// stderr and network output) and the listener so that clients can change
+// it at runtime with ToRobot::SetLogFilter
+pub(crate) struct SharedFilter {
+    filter: RwLock<(String, env_filter::Filter)>,
+    // only the logger installed by Logger::init may change log::max_level
+    global: AtomicBool,
+}
+
+impl SharedFilter {
+    // invalid directives are reported on stderr and ignored like RUST_LOG
+    pub(crate) fn new(spec: &str) -> Self {
+        let filter = env_filter::Builder::new().parse(spec).build();
+        Self {
+            filter: RwLock::new((spec.to_owned(), filter)),
+            global: AtomicBool::new(false),
+        }
+    }
+
+    // on error the current filter is kept
+    pub(crate) fn set(&self, spec: &str) -> Result<(), String> {
+        let filter = env_filter::Builder::new()
+            .try_parse(spec)
+            .map_err(|e| e.to_string())?
+            .build();
+        let max_level = filter.filter();
+        *self.filter.write().unwrap_or_else(|e| e.into_inner()) = (spec.to_owned(), filter);
+        if self.global.load(Ordering::Relaxed) {
+            log::set_max_level(max_level);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_global(&self) {
+        self.global.store(true, Ordering::Relaxed);
+        log::set_max_level(self.max_level());
+    }
+
+    pub(crate) fn spec(&self) -> String {
+        self.read(|(spec, _)| spec.clone())
+    }
+
+    pub(crate) fn max_level(&self) -> LevelFilter {
+        self.read(|(_, filter)| filter.filter())
+    }
+
+    pub(crate) fn enabled(&self, metadata: &Metadata) -> bool {
+        self.read(|(_, filter)| filter.enabled(metadata))
+    }
+
+    pub(crate) fn matches(&self, record: &Record) -> bool {
+        self.read(|(_, filter)| filter.matches(record))
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&(String, env_filter::Filter)) -> T) -> T {
+        f(&self.filter.read().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn enabled(filter: &SharedFilter, level: Level, target: &str) -> bool {
+        filter.enabled(&Metadata::builder().level(level).target(target).build())
+    }
+
+    #[test]
+    fn set_at_runtime() {
+        let filter = SharedFilter::new("info");
+        assert!(!enabled(&filter, Level::Debug, "drive"));
+
+        filter.set("warn,drive=debug").unwrap();
+        assert_eq!(filter.spec(), "warn,drive=debug");
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+        assert!(enabled(&filter, Level::Debug, "drive"));
+        assert!(!enabled(&filter, Level::Info, "intake"));
+
+        assert!(filter.set("drive=loud").is_err());
+        assert_eq!(filter.spec(), "warn,drive=debug");
+    }
+}
@@ -0,0 +1,257 @@
+use crate::{
+    packet::{self, ToClient},
+    recorder::{self, Recorded},
+    LoggerConfig,
+};
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+// log history kept by the listener for clients that connect (or reconnect)
+// late. Logs are addressed by their absolute index so that clients can
+// tell how many they missed once old logs have been evicted
+pub(crate) struct LogStore {
+    logs: VecDeque<(Instant, ToClient)>,
+    // absolute index of logs[0], i.e. how many logs have been evicted
+    first: u64,
+    max_logs: usize,
+    max_age: Option<Duration>,
+    // evicted logs are appended here as a recording, see
+    // recorder::read_recording
+    spill: Option<BufWriter<File>>,
+    spill_len: u64,
+    max_spill_len: u64,
+}
+
+impl LogStore {
+    // logs are kept in memory even if the spill file can't be opened
+    pub(crate) fn new(config: &LoggerConfig) -> Self {
+        let (spill, spill_len) = match config.log_spill_path.as_ref().map(open_spill) {
+            Some(Ok((file, len))) => (Some(BufWriter::new(file)), len),
+            Some(Err(e)) => {
+                log::error!("Failed to open log spill file, not spilling logs: {e}");
+                (None, 0)
+            }
+            None => (None, 0),
+        };
+        Self {
+            logs: VecDeque::new(),
+            first: 0,
+            max_logs: config.max_logs,
+            max_age: config.max_log_age,
+            spill,
+            spill_len,
+            max_spill_len: config.max_spill_len,
+        }
+    }
+
+    pub(crate) fn push(&mut self, log: ToClient) {
+        self.logs.push_back((Instant::now(), log));
+        self.evict();
+    }
+
+    // absolute index of the oldest log still stored
+    pub(crate) fn first(&self) -> u64 {
+        self.first
+    }
+
+    // logs from the absolute index cursor onwards, cursor must be >= first().
+    // Call evict first so that expired logs aren't replayed
+    pub(crate) fn since(&self, cursor: u64) -> impl Iterator<Item = &ToClient> {
+        let skip = usize::try_from(cursor - self.first).unwrap_or(usize::MAX);
+        self.logs.iter().skip(skip).map(|(_, log)| log)
+    }
+
+    pub(crate) fn flush(&mut self) {
+        if let Some(spill) = &mut self.spill {
+            let _ = spill.flush();
+        }
+    }
+
+    // logs are also evicted on push, but max_age can pass without any
+    pub(crate) fn evict(&mut self) {
+        let now = Instant::now();
+        while let Some((time, _)) = self.logs.front() {
+            let too_old = self
+                .max_age
+                .is_some_and(|max_age| now.duration_since(*time) > max_age);
+            if self.logs.len() <= self.max_logs && !too_old {
+                break;
+            }
+            let (time, log) = self.logs.pop_front().expect("front exists");
+            self.first += 1;
+            // when it was logged rather than when it was evicted
+            let timestamp = SystemTime::now()
+                .checked_sub(now.duration_since(time))
+                .unwrap_or_else(SystemTime::now);
+            self.spill(&Recorded {
+                timestamp,
+                pkt: log,
+            });
+        }
+    }
+
+    fn spill(&mut self, recorded: &Recorded) {
+        let Some(spill) = &mut self.spill else {
+            return;
+        };
+        // the length prefix and the body
+        let len = 4 + bincode::serialized_size(recorded).unwrap_or_default();
+        if self.spill_len + len > self.max_spill_len {
+            let _ = spill.flush();
+            self.spill = None;
+            log::warn!("Log spill file is full, no longer spilling");
+            return;
+        }
+        self.spill_len += len;
+        if let Err(e) = packet::send(spill, recorded) {
+            // stop spilling as logging every failure would itself create
+            // logs to spill
+            self.spill = None;
+            log::error!("Failed to spill logs to disk, no longer spilling: {e}");
+        }
+    }
+}
+
+// the file and its current length. Logs are appended to an earlier spill
+// from this version of the protocol, anything else is started over
+fn open_spill(path: &PathBuf) -> Result<(File, u64), packet::Error> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?;
+    let mut header = Vec::new();
+    recorder::write_header(&mut header)?;
+    let mut existing = Vec::new();
+    (&mut file)
+        .take(header.len() as u64)
+        .read_to_end(&mut existing)?;
+    if existing != header {
+        if !existing.is_empty() {
+            log::warn!("Log spill file {path:?} isn't from this version, starting it over");
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(&header)?;
+    }
+    let len = file.seek(SeekFrom::End(0))?;
+    Ok((file, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleLog;
+
+    fn log(msg: &str) -> ToClient {
+        ToClient::Log(SimpleLog {
+            level: log::Level::Info,
+            msg: String::from(msg),
+            target: String::from("test"),
+            timestamp: std::time::SystemTime::now(),
+            module_path: None,
+            file: None,
+            line: None,
+            fields: Default::default(),
+        })
+    }
+
+    fn msgs<'a>(logs: impl Iterator<Item = &'a ToClient>) -> Vec<&'a str> {
+        logs.map(|log| match log {
+            ToClient::Log(log) => log.msg.as_str(),
+            _ => unreachable!(),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn evicts_oldest() {
+        let mut store = LogStore::new(&LoggerConfig::default().max_logs(2));
+        for msg in ["a", "b", "c"] {
+            store.push(log(msg));
+        }
+        assert_eq!(store.first(), 1);
+        assert_eq!(msgs(store.since(1)), ["b", "c"]);
+        assert_eq!(msgs(store.since(2)), ["c"]);
+        assert_eq!(msgs(store.since(3)), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn evicts_expired() {
+        let config = LoggerConfig::default().max_log_age(Duration::from_millis(20));
+        let mut store = LogStore::new(&config);
+        store.push(log("old"));
+        std::thread::sleep(Duration::from_millis(30));
+        store.push(log("new"));
+        assert_eq!(store.first(), 1);
+        assert_eq!(msgs(store.since(1)), ["new"]);
+    }
+
+    #[test]
+    fn spills_to_disk() {
+        let path = std::env::temp_dir().join(format!("spill-test-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = LoggerConfig::default()
+            .max_logs(1)
+            .spill_logs_to(path.clone());
+        let mut store = LogStore::new(&config);
+        for msg in ["a", "b", "c"] {
+            store.push(log(msg));
+        }
+        store.flush();
+        // reopening appends to the same recording
+        let mut store = LogStore::new(&config);
+        for msg in ["d", "e"] {
+            store.push(log(msg));
+        }
+        store.flush();
+
+        let spilled = recorder::read_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let pkts: Vec<_> = spilled.into_iter().map(|r| r.pkt).collect();
+        assert_eq!(msgs(pkts.iter()), ["a", "b", "d"]);
+    }
+
+    #[test]
+    fn bounds_spill() {
+        let path = std::env::temp_dir().join(format!("spill-bound-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut header = Vec::new();
+        recorder::write_header(&mut header).unwrap();
+        let recorded = Recorded {
+            timestamp: SystemTime::now(),
+            pkt: log("a"),
+        };
+        let frame_len = 4 + bincode::serialized_size(&recorded).unwrap();
+        let max_len = header.len() as u64 + frame_len * 2;
+        let config = LoggerConfig::default()
+            .max_logs(0)
+            .spill_logs_to(path.clone())
+            .max_spill_len(max_len);
+        let mut store = LogStore::new(&config);
+        for msg in ["a", "b", "c"] {
+            store.push(log(msg));
+        }
+        store.flush();
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(len, max_len);
+    }
+
+    #[test]
+    fn expires_without_push() {
+        let config = LoggerConfig::default().max_log_age(Duration::from_millis(20));
+        let mut store = LogStore::new(&config);
+        store.push(log("old"));
+        std::thread::sleep(Duration::from_millis(30));
+        store.evict();
+        assert_eq!(store.first(), 1);
+        assert_eq!(msgs(store.since(1)), Vec::<&str>::new());
+    }
+}
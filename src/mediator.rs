@@ -1,6 +1,15 @@
-use crossbeam_channel::{Receiver, Sender, TrySendError};
+use crossbeam_channel::{Receiver, Sender, TryRecvError, TrySendError};
+use std::{net::SocketAddr, sync::Arc, thread::JoinHandle};
 
-use crate::packet::{self, FromMediator, ToMediator};
+use crate::{
+    client::ConnectionState,
+    listener::ClientRegistry,
+    packet::{self, ClientInfo, FromMediator, ToMediator},
+    params::{Param, ParamRegistry, ParamValue},
+    pid::{Gains, PidRegistry},
+    rpc::{self, Rpc},
+    Shared,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -10,26 +19,120 @@ pub enum Error {
     Packet(#[from] packet::Error),
     #[error("send error:\n{0}")]
     Send(#[from] TrySendError<FromMediator>),
+    #[error("listener thread panicked")]
+    ListenerPanicked,
+    #[error("listener thread has exited")]
+    ListenerExited,
+    #[error("invalid parameter:\n{0}")]
+    InvalidParam(String),
 }
 
 pub struct Mediator {
     send: Sender<FromMediator>,
     recv: Receiver<ToMediator>,
+    listener: JoinHandle<()>,
+    local_addr: SocketAddr,
+    pids: Arc<PidRegistry>,
+    params: Arc<ParamRegistry>,
+    clients: Arc<ClientRegistry>,
 }
 
 impl Mediator {
-    pub(crate) fn new(send: Sender<FromMediator>, recv: Receiver<ToMediator>) -> Self {
-        Self { send, recv }
+    pub(crate) fn new(
+        send: Sender<FromMediator>,
+        recv: Receiver<ToMediator>,
+        listener: JoinHandle<()>,
+        local_addr: SocketAddr,
+        shared: Shared,
+    ) -> Self {
+        Self {
+            send,
+            recv,
+            listener,
+            local_addr,
+            pids: shared.pids,
+            params: shared.params,
+            clients: shared.clients,
+        }
+    }
+    // flushes any queued logs and plots to the connected clients,
+    // disconnects them and waits for the listener thread to exit.
+    // Logging, plot! and odom still work afterwards but nothing is sent over
+    // the network
+    pub fn shutdown(self) -> Result<(), Error> {
+        // a blocking send so that a full channel can't lose the request,
+        // an error means the listener has already exited
+        let _ = self.send.send(FromMediator::Shutdown);
+        self.listener.join().map_err(|_| Error::ListenerPanicked)
     }
+    // the address clients connect to, e.g. to find the port picked when
+    // LoggerConfig::port is 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+    // requests from clients received since the last call. The listener
+    // reads clients on its own so this only needs calling when convenient.
+    // Errors once the listener has exited and every event has been returned
     pub fn poll_events(&mut self) -> Result<Vec<ToMediator>, Error> {
-        self.send_event(FromMediator::PollEvents)?;
-
         let mut events = Vec::new();
-        while let Ok(event) = self.recv.try_recv() {
-            events.push(event);
+        loop {
+            match self.recv.try_recv() {
+                Ok(event) => events.push(event),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) if events.is_empty() => {
+                    return Err(Error::ListenerExited)
+                }
+                Err(TryRecvError::Disconnected) => break,
+            }
         }
         Ok(events)
     }
+    // the clients connected right now, unlike ToMediator::ClientConnected
+    // and ClientDisconnected this is accurate even if events were dropped
+    // because poll_events wasn't called often enough
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.clients.list()
+    }
+    // makes a PID controller tunable by clients, registering an existing
+    // name replaces its gains
+    pub fn register_pid(&mut self, name: impl Into<String>, gains: Gains) {
+        self.pids.register(name.into(), gains);
+    }
+    // the current gains of a registered controller including any changes
+    // made by clients, which are also reported as ToMediator::PidChanged
+    pub fn pid(&self, name: &str) -> Option<Gains> {
+        self.pids.get(name).ok()
+    }
+    // makes a parameter readable and writable by clients, declaring an
+    // existing name replaces it. Errors if the range is invalid, set on
+    // anything but numbers or doesn't contain the value
+    pub fn declare_param(&mut self, name: impl Into<String>, param: Param) -> Result<(), Error> {
+        self.params
+            .declare(name.into(), param)
+            .map_err(Error::InvalidParam)
+    }
+    // the current value of a declared parameter including any changes made
+    // by clients, which are also reported as ToMediator::ParamChanged.
+    // None if it isn't declared or is a different type
+    pub fn param<T: TryFrom<ParamValue>>(&self, name: &str) -> Option<T> {
+        T::try_from(self.params.get(name).ok()?).ok()
+    }
+    // changes a declared parameter, the listener then notifies the
+    // subscribed clients
+    pub fn set_param(&mut self, name: &str, value: impl Into<ParamValue>) -> Result<(), Error> {
+        self.params
+            .set(name, value.into())
+            .map(|_| ())
+            .map_err(Error::InvalidParam)
+    }
+    // Connected while any client is. See ToMediator::ClientDisconnected to
+    // react to a particular client
+    pub fn connection_state(&self) -> ConnectionState {
+        match self.clients.is_empty() {
+            true => ConnectionState::Disconnected,
+            false => ConnectionState::Connected,
+        }
+    }
     pub fn send_events(&mut self, events: Vec<FromMediator>) -> Result<(), Error> {
         for event in events {
             self.send.try_send(event)?;
@@ -39,4 +142,33 @@ impl Mediator {
     pub fn send_event(&mut self, event: FromMediator) -> Result<(), Error> {
         Ok(self.send.try_send(event)?)
     }
+    // answers a ToMediator::Request, see rpc::Request::parse
+    pub fn respond<R: Rpc>(
+        &mut self,
+        req: &rpc::Request,
+        response: &R::Response,
+    ) -> Result<(), Error> {
+        debug_assert_eq!(req.method, R::METHOD, "responding to the wrong request");
+        let payload = bincode::serialize(response).map_err(packet::Error::from)?;
+        self.send_response(req, Ok(payload))
+    }
+    // e.g. for unknown methods or requests that fail to parse
+    pub fn respond_err(
+        &mut self,
+        req: &rpc::Request,
+        error: impl Into<String>,
+    ) -> Result<(), Error> {
+        self.send_response(req, Err(error.into()))
+    }
+    fn send_response(
+        &mut self,
+        req: &rpc::Request,
+        result: Result<Vec<u8>, String>,
+    ) -> Result<(), Error> {
+        self.send_event(FromMediator::Response {
+            client: req.client,
+            id: req.id,
+            result,
+        })
+    }
 }
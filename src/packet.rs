@@ -1,14 +1,16 @@
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use std::{
+    collections::BTreeMap,
     convert::Into,
     io::{Read, Write},
-    net::TcpStream,
+    net::{SocketAddr, TcpStream},
+    ops::BitOr,
 };
 
-use log::{Level, Record};
+use log::{kv, Level, LevelFilter, Record};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::{path::Action, plot};
+use crate::{params, path::Action, pid, plot, rpc, telemetry};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -16,10 +18,109 @@ pub enum Error {
     Bincode(#[from] bincode::Error),
     #[error("read/write error:\n{0}")]
     Io(#[from] std::io::Error),
+    #[error("frame of {len} bytes exceeds the maximum of {max} bytes")]
+    FrameTooLarge { len: usize, max: usize },
+    #[error("malformed packet:\n{0}")]
+    Malformed(bincode::Error),
+    #[error("protocol version mismatch: local version is {local}, remote version is {remote}")]
+    VersionMismatch { local: u32, remote: u32 },
+    #[error("nothing received from peer for {0:?}")]
+    HeartbeatTimeout(Duration),
+    #[error("{0} bytes are waiting to be sent to a peer that isn't reading them")]
+    Backlog(usize),
     #[error("unknown error:\n{0}")]
     Other(String),
 }
 
+// bump this whenever the layout of ToClient, ToRobot or anything they
+// contain changes so that mismatched peers refuse each other instead of
+// silently mis-decoding packets
+pub const PROTOCOL_VERSION: u32 = 13;
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+// plenty for any real packet while stopping a corrupt length prefix from
+// making us allocate gigabytes
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1 << 20;
+// a Hello is only a few bytes
+pub(crate) const MAX_HELLO_LEN: usize = 64;
+
+// both peers send a heartbeat every interval and hang up on a peer they
+// haven't received anything from for timeout, so a peer that silently goes
+// away (e.g. the laptop's wifi dropping) is noticed even when nothing is
+// being sent to it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(200),
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+// optional features a peer supports, the intersection of both peers
+// capabilities is what ends up being used for a connection
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    pub const LOGS: Self = Self(1 << 0);
+    pub const PLOTS: Self = Self(1 << 1);
+    pub const ODOMETRY: Self = Self(1 << 2);
+    pub const PATHS: Self = Self(1 << 3);
+    pub const PID: Self = Self(1 << 4);
+    // ToClient::ExtendedLog, clients without it get the plain SimpleLog
+    pub const EXTENDED_LOGS: Self = Self(1 << 5);
+    // ToClient::Params, ToClient::Param and ToClient::ParamChanged
+    pub const PARAMS: Self = Self(1 << 6);
+    pub const ALL: Self = Self(
+        Self::LOGS.0
+            | Self::PLOTS.0
+            | Self::ODOMETRY.0
+            | Self::PATHS.0
+            | Self::PID.0
+            | Self::EXTENDED_LOGS.0
+            | Self::PARAMS.0,
+    );
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+// first packet sent by both peers on connect. It is sent as a bare struct
+// rather than through ToClient/ToRobot so that its layout doesn't depend
+// on the layout of those enums
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct Hello {
+    pub version: u32,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+}
+
 // see https://serde.rs/remote-derive.html
 // and https://docs.rs/log/latest/src/log/lib.rs.html#429-453
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
@@ -32,6 +133,17 @@ enum LevelDef {
     Trace,
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(remote = "LevelFilter")]
+enum LevelFilterDef {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct SimpleLog {
     #[serde(with = "LevelDef")]
@@ -39,19 +151,84 @@ pub struct SimpleLog {
     pub msg: String,
     pub target: String,
     pub timestamp: SystemTime,
+    pub module_path: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    // key/values of the record e.g. log::info!(motor = 3; "stalled")
+    pub fields: Fields,
 }
 
 impl From<&Record<'_>> for SimpleLog {
     fn from(rec: &Record<'_>) -> Self {
+        let mut fields = FieldsVisitor(Fields::new());
+        // only fails if the visitor does
+        let _ = rec.key_values().visit(&mut fields);
         Self {
             level: rec.level(),
             msg: rec.args().to_string(),
             target: rec.target().to_owned(),
             timestamp: SystemTime::now(),
+            module_path: rec.module_path().map(str::to_owned),
+            file: rec.file().map(str::to_owned),
+            line: rec.line(),
+            fields: fields.0,
+        }
+    }
+}
+
+struct FieldsVisitor(Fields);
+
+impl<'kvs> kv::VisitSource<'kvs> for FieldsVisitor {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(key.to_string(), Value::from(value));
+        Ok(())
+    }
+}
+
+// value of a structured field attached to a log or span
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum Value {
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Str(String),
+}
+
+// anything that isn't a primitive is sent as its Display output
+impl From<kv::Value<'_>> for Value {
+    fn from(value: kv::Value<'_>) -> Self {
+        if let Some(v) = value.to_bool() {
+            Self::Bool(v)
+        } else if let Some(v) = value.to_i64() {
+            Self::I64(v)
+        } else if let Some(v) = value.to_u64() {
+            Self::U64(v)
+        } else if let Some(v) = value.to_f64() {
+            Self::F64(v)
+        } else {
+            Self::Str(value.to_string())
         }
     }
 }
 
+pub type Fields = BTreeMap<String, Value>;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct SpanContext {
+    pub name: String,
+    pub target: String,
+    pub fields: Fields,
+}
+
+// a log with the spans it was emitted in (outermost first). Produced by the
+// tracing layer
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ExtendedLog {
+    pub log: SimpleLog,
+    pub spans: Vec<SpanContext>,
+}
+
 // TCP PACKETS
 // #[repr(u8)] + discriminants are to mitigate version compatability problems
 // although code should ideally always run with the same version
@@ -59,87 +236,364 @@ impl From<&Record<'_>> for SimpleLog {
 #[repr(u8)]
 pub enum ToClient {
     Log(SimpleLog) = 0,
-    Pong = 1,
+    // reply to ToRobot::Ping with the same seq, sent once by each of source
+    Pong {
+        seq: u64,
+        source: PongSource,
+    } = 1,
     Path(Vec<Action>) = 2,
     PointBuffer((plot::Names, plot::Buffer)) = 3,
     // (first_robot, pos, heading)
     Odometry((bool, [f64; 2], f64)) = 4,
+    ExtendedLog(ExtendedLog) = 5,
+    // this many logs were evicted from the robot's history before they
+    // could be sent to this client
+    LogsDropped(u64) = 6,
+    // reply to ToRobot::SetLogFilter, the filter now in effect or why the
+    // requested one was rejected
+    LogFilter(Result<String, String>) = 7,
+    // telemetry discarded on the robot since the last notice because the
+    // listener fell behind, and events because the main loop did
+    Dropped(telemetry::Dropped) = 8,
+    // reply to ToRobot::Request with the same id, the bincode encoded
+    // rpc::Rpc::Response or the error the robot answered with
+    Response {
+        id: u64,
+        result: Result<Vec<u8>, String>,
+    } = 9,
+    // see Heartbeat, never returned by Client::receive_data
+    Heartbeat = 10,
+    // reply to ToRobot::GetPid and ToRobot::SetPid, the gains now in effect
+    // or why the request was rejected
+    Pid {
+        name: String,
+        result: Result<pid::Gains, String>,
+    } = 11,
+    // reply to ToRobot::ListPids
+    Pids(Vec<(String, pid::Gains)>) = 12,
+    // reply to ToRobot::ListParams
+    Params(Vec<(String, params::Param)>) = 13,
+    // reply to ToRobot::GetParam and ToRobot::SetParam, the value now in
+    // effect or why the request was rejected
+    Param {
+        name: String,
+        result: Result<params::ParamValue, String>,
+    } = 14,
+    // a parameter this client subscribed to changed, either by a client or
+    // by robot code
+    ParamChanged {
+        name: String,
+        value: params::ParamValue,
+    } = 15,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 #[repr(u8)]
 pub enum ToRobot {
     RequestLogs = 0,
-    Ping = 1,
+    // seq is echoed back in both Pongs, see Client::ping
+    Ping(u64) = 1,
     Path(Vec<Action>) = 2,
     Pid((f64, f64, f64)) = 3,
+    // only logs at least this important are sent to this client
+    SetLogLevel(#[serde(with = "LevelFilterDef")] LevelFilter) = 4,
+    // only logs whose target or module path is one of these modules or
+    // their submodules are sent to this client, empty sends everything
+    SetLogTargets(Vec<String>) = 5,
+    // replaces the robot's own log filter (RUST_LOG syntax) for both its
+    // stderr and every client, unlike SetLogLevel and SetLogTargets
+    SetLogFilter(String) = 6,
+    // a call of an rpc::Rpc, payload is its bincode encoded Request. See
+    // Client::call
+    Request {
+        id: u64,
+        method: String,
+        payload: Vec<u8>,
+    } = 7,
+    // see Heartbeat, sent by Client::receive_data
+    Heartbeat = 8,
+    // gains of a controller registered with Mediator::register_pid
+    GetPid(String) = 9,
+    SetPid((String, pid::Gains)) = 10,
+    ListPids = 11,
+    // parameters declared with Mediator::declare_param
+    ListParams = 12,
+    GetParam(String) = 13,
+    SetParam((String, params::ParamValue)) = 14,
+    // replaces the parameters this client is sent ToClient::ParamChanged
+    // for, their current values are sent straight away
+    SubscribeParams(Vec<String>) = 15,
+}
+
+// the listener thread answers a ping as soon as it is read, the mediator
+// answers once the robot code handles the ToMediator::Ping. The difference
+// between the two round trip times is how long the main loop took to get
+// around to it
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum PongSource {
+    Listener,
+    Mediator,
 }
 
 // THREAD PACKETS
+// answer with FromMediator::Pong(ping)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ping {
+    pub(crate) client: rpc::ClientId,
+    pub(crate) seq: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub id: rpc::ClientId,
+    pub addr: SocketAddr,
+    pub capabilities: Capabilities,
+}
+
 #[derive(Debug)]
 pub enum ToMediator {
     Path(Vec<Action>),
     Pid((f64, f64, f64)),
-    Ping,
+    // a client changed the gains of a registered controller, see
+    // Mediator::pid
+    PidChanged {
+        name: String,
+        gains: pid::Gains,
+    },
+    // a client changed a parameter, see Mediator::param
+    ParamChanged {
+        name: String,
+        value: params::ParamValue,
+    },
+    Ping(Ping),
+    // answer with Mediator::respond or Mediator::respond_err
+    Request(rpc::Request),
+    // e.g. to re-send the current path so a new GUI is up to date
+    ClientConnected(ClientInfo),
+    // the client hung up, errored or timed out (see Heartbeat), reason is
+    // the error it was disconnected with
+    ClientDisconnected {
+        client: ClientInfo,
+        reason: String,
+    },
 }
 
 #[derive(Debug)]
 pub enum FromMediator {
-    Log(SimpleLog),
+    // logs are boxed so FromMediator (and errors carrying it) stays small
+    Log(Box<SimpleLog>),
+    ExtendedLog(Box<ExtendedLog>),
     Path(Vec<Action>),
-    Pong,
-    PollEvents,
+    // sent to the client that sent the ping
+    Pong(Ping),
     Point((plot::Names, plot::Point)),
+    // (plot name, settings), see plot::configure
+    PlotSettings((String, plot::BufferSettings)),
     Odometry(([f64; 2], f64)),
+    // sent by Mediator::respond to the client that made the request
+    Response {
+        client: rpc::ClientId,
+        id: u64,
+        result: Result<Vec<u8>, String>,
+    },
+    Shutdown,
 }
 
 impl From<&Record<'_>> for FromMediator {
     fn from(rec: &Record<'_>) -> Self {
-        Self::Log(rec.into())
+        Self::Log(Box::new(rec.into()))
     }
 }
 
-pub(crate) fn send(stream: &mut std::net::TcpStream, pkt: &impl Serialize) -> Result<(), Error> {
+pub(crate) fn send(stream: &mut impl Write, pkt: &impl Serialize) -> Result<(), Error> {
     let data = bincode::serialize(pkt)?;
     let len = u32::try_from(data.len())
         .map_err(|_| Error::Other(String::from("Packet length greater then 2^32-1 bytes?!?")))?;
-    stream.write_all(&len.to_be_bytes())?;
-    stream.write_all(&data)?;
+    // written in one go so that Nagle's algorithm can't hold back the body
+    // of a small packet after its length has been sent
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&data);
+    stream.write_all(&frame)?;
     Ok(())
 }
 
+// exchanges Hellos with the peer and returns the negotiated capabilities.
+// both sides send before reading so neither can deadlock waiting on the other
+pub(crate) fn handshake(
+    stream: &mut TcpStream,
+    capabilities: Capabilities,
+) -> Result<Capabilities, Error> {
+    handshake_timeout(stream, capabilities, HANDSHAKE_TIMEOUT)
+}
+
+// handshake giving up after timeout, which must not be zero
+pub(crate) fn handshake_timeout(
+    stream: &mut TcpStream,
+    capabilities: Capabilities,
+    timeout: Duration,
+) -> Result<Capabilities, Error> {
+    let deadline = Instant::now() + timeout;
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.set_read_timeout(Some(timeout))?;
+    send(stream, &Hello::new(capabilities))?;
+    // whatever is left after sending
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    stream.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+    let remote: Hello = recieve_blocking(stream)?;
+    stream.set_write_timeout(None)?;
+    stream.set_read_timeout(None)?;
+    stream.set_nonblocking(true)?;
+    negotiate(capabilities, remote)
+}
+
+// the receiving half of handshake for a non-blocking stream whose Hello
+// has already been sent, so that a slow peer can't stall the caller.
+// Returns None until the peer's Hello has arrived, anything sent after it
+// is left in decoder
+pub(crate) fn poll_hello(
+    stream: &mut impl Read,
+    decoder: &mut FrameDecoder,
+    capabilities: Capabilities,
+) -> Result<Option<Capabilities>, Error> {
+    let mut chunk = [0u8; READ_CHUNK_SIZE];
+    loop {
+        if let Some(frame) = decoder.next_frame()? {
+            let remote = bincode::deserialize(&frame).map_err(Error::Malformed)?;
+            return negotiate(capabilities, remote).map(Some);
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "connection closed by peer",
+                )))
+            }
+            Ok(n) => decoder.push(&chunk[..n]),
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::Io(e)),
+        }
+    }
+}
+
+fn negotiate(capabilities: Capabilities, remote: Hello) -> Result<Capabilities, Error> {
+    if remote.version != PROTOCOL_VERSION {
+        return Err(Error::VersionMismatch {
+            local: PROTOCOL_VERSION,
+            remote: remote.version,
+        });
+    }
+    Ok(capabilities.intersection(remote.capabilities))
+}
+
+fn recieve_blocking<T: DeserializeOwned>(stream: &mut TcpStream) -> Result<T, Error> {
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_HELLO_LEN {
+        return Err(Error::FrameTooLarge {
+            len,
+            max: MAX_HELLO_LEN,
+        });
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf)?;
+    bincode::deserialize(&buf).map_err(Error::Malformed)
+}
+
+// size of the chunks read from the stream in recieve_multiple
+const READ_CHUNK_SIZE: usize = 4096;
+
+// incrementally splits bytes from a non-blocking stream into frames. A frame
+// is a 4 byte big endian length followed by that many bytes of bincode. Any
+// partial frame is kept until the rest of it arrives.
+// There is no way to find the start of the next frame after a bad length
+// prefix so errors are unrecoverable and the connection should be dropped
+#[derive(Debug)]
+pub(crate) struct FrameDecoder {
+    buf: Vec<u8>,
+    max_frame_len: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_LEN)
+    }
+}
+
+impl FrameDecoder {
+    pub(crate) fn new(max_frame_len: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            max_frame_len,
+        }
+    }
+    pub(crate) fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len;
+    }
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+    // returns the body of the next complete frame if there is one
+    pub(crate) fn next_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let Some(len_buf) = self.buf.first_chunk::<4>() else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(*len_buf) as usize;
+        if len > self.max_frame_len {
+            // checked before waiting on the body so it is never buffered
+            self.buf.clear();
+            return Err(Error::FrameTooLarge {
+                len,
+                max: self.max_frame_len,
+            });
+        }
+        if self.buf.len() < 4 + len {
+            return Ok(None);
+        }
+        let frame = self.buf[4..4 + len].to_vec();
+        self.buf.drain(..4 + len);
+        Ok(Some(frame))
+    }
+}
+
+// reads everything currently available on a non-blocking stream and calls
+// pkt_fn for every complete packet. Never blocks, partial packets are kept
+// in decoder until the next call
 pub(crate) fn recieve_multiple<
+    S: Read,
     T: DeserializeOwned,
     E: std::error::Error + From<Error>,
-    F: FnMut(&mut TcpStream, T) -> Result<(), E>,
+    F: FnMut(&mut S, T) -> Result<(), E>,
 >(
-    stream: &mut TcpStream,
+    stream: &mut S,
+    decoder: &mut FrameDecoder,
     pkt_fn: &mut F,
 ) -> Result<(), E> {
-    let mut len_buf = [0u8; 4];
-    stream.set_nonblocking(true).map_err(Into::into)?;
+    let mut chunk = [0u8; READ_CHUNK_SIZE];
     loop {
-        let pkt: T = {
-            match stream.read_exact(&mut len_buf) {
-                Ok(()) => {}
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    break;
-                }
-                Err(e) => return Err(Error::Io(e).into()),
-            }
-
-            let len = u32::from_be_bytes(len_buf);
-            let len = usize::try_from(len)
-                .map_err(|_| Error::Other(String::from("Packet larger then pointer size?!?")))?;
-            let mut buf = vec![0u8; len];
-            stream.set_nonblocking(false).map_err(Into::into)?;
-            stream.read_exact(&mut buf).map_err(Into::into)?;
-            stream.set_nonblocking(true).map_err(Into::into)?;
-
-            bincode::deserialize(&buf).map_err(Into::into)?
-        };
+        // frames can already be buffered, e.g. ones sent straight after a
+        // Hello (see poll_hello)
+        while let Some(frame) = decoder.next_frame()? {
+            let pkt: T = bincode::deserialize(&frame).map_err(Error::Malformed)?;
+            pkt_fn(stream, pkt)?;
+        }
 
-        pkt_fn(stream, pkt)?;
+        match stream.read(&mut chunk) {
+            Ok(0) => {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "connection closed by peer",
+                ))
+                .into())
+            }
+            Ok(n) => decoder.push(&chunk[..n]),
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::Io(e).into()),
+        }
     }
     Ok(())
 }
@@ -155,8 +609,195 @@ mod tests {
             msg: String::from("test"),
             target: String::from("test2"),
             timestamp: std::time::SystemTime::now(),
+            module_path: Some(String::from("communication::packet::tests")),
+            file: Some(String::from(file!())),
+            line: Some(line!()),
+            fields: Fields::from([(String::from("motor"), Value::U64(3))]),
         });
         let data = bincode::serialize(&test_val).unwrap();
         assert_eq!(test_val, bincode::deserialize(&data).unwrap());
     }
+
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (listener.accept().unwrap().0, client)
+    }
+
+    // hands out one byte per read with a WouldBlock in between, like a
+    // very slow non-blocking socket
+    struct Trickle {
+        data: Vec<u8>,
+        pos: usize,
+        would_block: bool,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.would_block = !self.would_block;
+            if self.would_block || self.pos == self.data.len() {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            buf[0] = self.data[self.pos];
+            self.pos += 1;
+            Ok(1)
+        }
+    }
+
+    fn frame(pkt: &impl Serialize) -> Vec<u8> {
+        let data = bincode::serialize(pkt).unwrap();
+        let mut out = (data.len() as u32).to_be_bytes().to_vec();
+        out.extend(data);
+        out
+    }
+
+    #[test]
+    fn record_key_values() {
+        let kvs: [(&str, kv::Value); 4] = [
+            ("motor", 3.into()),
+            ("speed", 1.5.into()),
+            ("reversed", true.into()),
+            ("side", "left".into()),
+        ];
+        let log = SimpleLog::from(
+            &Record::builder()
+                .args(format_args!("stalled"))
+                .level(Level::Warn)
+                .target("drive")
+                .module_path_static(Some("drive::motors"))
+                .file_static(Some("drive.rs"))
+                .line(Some(42))
+                .key_values(&kvs)
+                .build(),
+        );
+        assert_eq!(log.msg, "stalled");
+        assert_eq!(log.module_path.as_deref(), Some("drive::motors"));
+        assert_eq!(log.file.as_deref(), Some("drive.rs"));
+        assert_eq!(log.line, Some(42));
+        assert_eq!(
+            log.fields,
+            Fields::from([
+                (String::from("motor"), Value::I64(3)),
+                (String::from("speed"), Value::F64(1.5)),
+                (String::from("reversed"), Value::Bool(true)),
+                (String::from("side"), Value::Str(String::from("left"))),
+            ])
+        );
+    }
+
+    #[test]
+    fn decode_byte_by_byte() {
+        let pkts = [
+            ToRobot::Ping(7),
+            ToRobot::Pid((1.0, 2.0, 3.0)),
+            ToRobot::RequestLogs,
+        ];
+        let mut decoder = FrameDecoder::default();
+        let mut decoded = Vec::new();
+        for byte in pkts.iter().flat_map(frame) {
+            assert!(decoded.len() < pkts.len());
+            decoder.push(&[byte]);
+            if let Some(frame) = decoder.next_frame().unwrap() {
+                decoded.push(bincode::deserialize::<ToRobot>(&frame).unwrap());
+            }
+        }
+        assert_eq!(decoded, pkts);
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut decoder = FrameDecoder::new(16);
+        decoder.push(&u32::MAX.to_be_bytes());
+        match decoder.next_frame() {
+            Err(Error::FrameTooLarge { len, max: 16 }) => assert_eq!(len, u32::MAX as usize),
+            other => panic!("expected oversized frame error, got {other:?}"),
+        }
+
+        let mut decoder = FrameDecoder::new(16);
+        decoder.push(&frame(&ToRobot::Path(vec![
+            Action::MoveRel { rel: 1.0 };
+            4
+        ])));
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(Error::FrameTooLarge { max: 16, .. })
+        ));
+    }
+
+    #[test]
+    fn malformed_frame() {
+        // variant 200 doesn't exist
+        let mut stream = Trickle {
+            data: frame(&200u32),
+            pos: 0,
+            would_block: false,
+        };
+        let mut decoder = FrameDecoder::default();
+        let mut pkt_fn = |_: &mut _, _: ToRobot| -> Result<(), Error> { Ok(()) };
+        let res = loop {
+            match recieve_multiple(&mut stream, &mut decoder, &mut pkt_fn) {
+                Ok(()) if stream.pos < stream.data.len() => {}
+                res => break res,
+            }
+        };
+        assert!(matches!(res, Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn recieve_multiple_partial_reads() {
+        let pkts = [ToRobot::Path(Vec::new()), ToRobot::Ping(7)];
+        let mut stream = Trickle {
+            data: pkts.iter().flat_map(frame).collect(),
+            pos: 0,
+            would_block: false,
+        };
+        let mut decoder = FrameDecoder::default();
+        let mut decoded = Vec::new();
+        let mut pkt_fn = |_: &mut _, pkt: ToRobot| -> Result<(), Error> {
+            decoded.push(pkt);
+            Ok(())
+        };
+        // every call reads a single byte before hitting WouldBlock
+        while stream.pos < stream.data.len() {
+            recieve_multiple(&mut stream, &mut decoder, &mut pkt_fn).unwrap();
+        }
+        assert_eq!(decoded, pkts);
+    }
+
+    #[test]
+    fn handshake_negotiates_capabilities() {
+        let (mut robot, mut client) = connected_pair();
+        let thread = std::thread::spawn(move || handshake(&mut robot, Capabilities::ALL));
+
+        let caps = handshake(&mut client, Capabilities::LOGS | Capabilities::PLOTS).unwrap();
+        assert_eq!(caps, Capabilities::LOGS | Capabilities::PLOTS);
+        assert_eq!(thread.join().unwrap().unwrap(), caps);
+        assert!(!caps.contains(Capabilities::ODOMETRY));
+    }
+
+    #[test]
+    fn handshake_version_mismatch() {
+        let (mut robot, mut client) = connected_pair();
+        let thread = std::thread::spawn(move || handshake(&mut robot, Capabilities::ALL));
+
+        send(
+            &mut client,
+            &Hello {
+                version: PROTOCOL_VERSION + 1,
+                capabilities: Capabilities::ALL,
+            },
+        )
+        .unwrap();
+        let hello: Hello = recieve_blocking(&mut client).unwrap();
+        assert_eq!(hello.version, PROTOCOL_VERSION);
+
+        match thread.join().unwrap() {
+            Err(Error::VersionMismatch { local, remote }) => {
+                assert_eq!(local, PROTOCOL_VERSION);
+                assert_eq!(remote, PROTOCOL_VERSION + 1);
+            }
+            other => panic!("expected version mismatch, got {other:?}"),
+        }
+    }
 }
@@ -0,0 +1,269 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum ParamValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Vec(Vec<f64>),
+}
+
+impl ParamValue {
+    fn same_type(&self, other: &Self) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(self, Self::Int(_) | Self::Float(_) | Self::Vec(_))
+    }
+
+    // whether every number in the value is finite and within the inclusive
+    // range. Ints are compared as ints so large values aren't rounded
+    fn in_range(&self, (min, max): (f64, f64)) -> bool {
+        let float_in_range = |v: f64| v >= min && v <= max;
+        match self {
+            // casts from floats saturate, so infinite bounds still work
+            Self::Int(v) => (min.ceil() as i128..=max.floor() as i128).contains(&i128::from(*v)),
+            Self::Float(v) => float_in_range(*v),
+            Self::Vec(v) => v.iter().all(|v| float_in_range(*v)),
+            Self::Bool(_) | Self::Str(_) => true,
+        }
+    }
+}
+
+macro_rules! param_type {
+    ($t:ty, $variant:ident) => {
+        impl From<$t> for ParamValue {
+            fn from(value: $t) -> Self {
+                Self::$variant(value)
+            }
+        }
+
+        impl TryFrom<ParamValue> for $t {
+            type Error = ParamValue;
+            fn try_from(value: ParamValue) -> Result<Self, ParamValue> {
+                match value {
+                    ParamValue::$variant(v) => Ok(v),
+                    value => Err(value),
+                }
+            }
+        }
+    };
+}
+
+param_type!(bool, Bool);
+param_type!(i64, Int);
+param_type!(f64, Float);
+param_type!(String, Str);
+param_type!(Vec<f64>, Vec);
+
+impl From<&str> for ParamValue {
+    fn from(value: &str) -> Self {
+        Self::Str(value.to_owned())
+    }
+}
+
+// a parameter declared by robot code with Mediator::declare_param, e.g.
+// Param::new(0.6).range(0.0, 1.0)
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Param {
+    pub value: ParamValue,
+    pub default: ParamValue,
+    // inclusive (min, max) of ints, floats and every element of vecs
+    pub range: Option<(f64, f64)>,
+}
+
+impl Param {
+    pub fn new(default: impl Into<ParamValue>) -> Self {
+        let default = default.into();
+        Self {
+            value: default.clone(),
+            default,
+            range: None,
+        }
+    }
+    pub fn range(mut self, min: f64, max: f64) -> Self {
+        self.range = Some((min, max));
+        self
+    }
+
+    // checked when the parameter is declared
+    fn check(&self) -> Result<(), String> {
+        if let Some((min, max)) = self.range {
+            if !self.default.is_number() {
+                return Err(format!("{:?} can't have a range", self.default));
+            }
+            if min.is_nan() || max.is_nan() || min > max {
+                return Err(String::from("range must be (min, max) with min <= max"));
+            }
+        }
+        self.validate(&self.default)
+            .map_err(|e| format!("invalid default: {e}"))?;
+        self.validate(&self.value)
+    }
+
+    fn validate(&self, value: &ParamValue) -> Result<(), String> {
+        if !value.same_type(&self.default) {
+            return Err(format!("expected a value like {:?}", self.default));
+        }
+        if !value.in_range((f64::MIN, f64::MAX)) {
+            return Err(String::from("value must be finite"));
+        }
+        match self.range {
+            Some((min, max)) if !value.in_range((min, max)) => {
+                Err(format!("value must be between {min} and {max}"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// the parameters robot code has declared, shared with the listener so that
+// clients are answered without waiting on the main loop
+#[derive(Debug, Default)]
+pub(crate) struct ParamRegistry {
+    params: RwLock<BTreeMap<String, Param>>,
+    // set since the listener last notified subscribers, whoever set them
+    changed: Mutex<BTreeSet<String>>,
+}
+
+impl ParamRegistry {
+    // replaces the parameter if name is already declared, errors if its
+    // range is invalid or doesn't contain its value
+    pub(crate) fn declare(&self, name: String, param: Param) -> Result<(), String> {
+        param.check()?;
+        self.write().insert(name, param);
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<ParamValue, String> {
+        self.read()
+            .get(name)
+            .map(|param| param.value.clone())
+            .ok_or_else(|| format!("no parameter named {name}"))
+    }
+
+    // only declared parameters can be set, on error the value is kept
+    pub(crate) fn set(&self, name: &str, value: ParamValue) -> Result<ParamValue, String> {
+        let mut params = self.write();
+        let param = params
+            .get_mut(name)
+            .ok_or_else(|| format!("no parameter named {name}"))?;
+        param.validate(&value)?;
+        param.value = value.clone();
+        self.changed().insert(name.to_owned());
+        Ok(value)
+    }
+
+    // the current value of every parameter set since the last call
+    pub(crate) fn take_changed(&self) -> Vec<(String, ParamValue)> {
+        let changed = std::mem::take(&mut *self.changed());
+        let params = self.read();
+        changed
+            .into_iter()
+            .filter_map(|name| {
+                let value = params.get(&name)?.value.clone();
+                Some((name, value))
+            })
+            .collect()
+    }
+
+    pub(crate) fn list(&self) -> Vec<(String, Param)> {
+        self.read()
+            .iter()
+            .map(|(name, param)| (name.clone(), param.clone()))
+            .collect()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<String, Param>> {
+        self.params.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<String, Param>> {
+        self.params.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn changed(&self) -> MutexGuard<'_, BTreeSet<String>> {
+        self.changed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_validates() {
+        let params = ParamRegistry::default();
+        params
+            .declare(String::from("speed"), Param::new(0.5).range(0.0, 1.0))
+            .unwrap();
+        params
+            .declare(String::from("auton"), Param::new("left"))
+            .unwrap();
+        params
+            .declare(
+                String::from("offsets"),
+                Param::new(vec![0.0, 0.0]).range(-5.0, 5.0),
+            )
+            .unwrap();
+
+        assert!(params.set("turn_speed", 0.2.into()).is_err());
+        assert!(params.set("speed", 1.5.into()).is_err());
+        assert!(params.set("speed", f64::NAN.into()).is_err());
+        assert!(params.set("speed", 1i64.into()).is_err());
+        assert!(params.set("offsets", vec![1.0, 6.0].into()).is_err());
+        assert_eq!(params.get("speed"), Ok(ParamValue::Float(0.5)));
+
+        assert_eq!(params.set("speed", 0.8.into()), Ok(ParamValue::Float(0.8)));
+        assert!(params.set("auton", "right".into()).is_ok());
+        assert_eq!(f64::try_from(params.get("speed").unwrap()), Ok(0.8));
+        assert_eq!(
+            params.take_changed(),
+            [
+                (String::from("auton"), ParamValue::from("right")),
+                (String::from("speed"), ParamValue::Float(0.8)),
+            ]
+        );
+        assert!(params.take_changed().is_empty());
+        let list = params.list();
+        assert_eq!(list.len(), 3);
+        assert_eq!(list[0].0, "auton");
+        assert_eq!(list[0].1.value, ParamValue::from("right"));
+        assert_eq!(list[0].1.default, ParamValue::from("left"));
+    }
+
+    #[test]
+    fn declare_validates() {
+        let params = ParamRegistry::default();
+        let declare = |param| params.declare(String::from("p"), param);
+        assert!(declare(Param::new(2.0).range(0.0, 1.0)).is_err());
+        assert!(declare(Param::new(0.5).range(1.0, 0.0)).is_err());
+        assert!(declare(Param::new(0.5).range(f64::NAN, 1.0)).is_err());
+        assert!(declare(Param::new(f64::INFINITY)).is_err());
+        assert!(declare(Param::new(true).range(0.0, 1.0)).is_err());
+        assert!(declare(Param::new("left").range(0.0, 1.0)).is_err());
+        assert!(declare(Param::new(vec![0.5, 2.0]).range(0.0, 1.0)).is_err());
+        assert!(params.list().is_empty());
+
+        params
+            .declare(String::from("ticks"), Param::new(1i64).range(0.5, 1e300))
+            .unwrap();
+        assert!(params.set("ticks", i64::MAX.into()).is_ok());
+        assert!(params.set("ticks", 0i64.into()).is_err());
+        // 2^53 + 1 would round down to the bound as a float
+        params
+            .declare(
+                String::from("max"),
+                Param::new(0i64).range(0.0, 9007199254740992.0),
+            )
+            .unwrap();
+        assert!(params.set("max", 9007199254740993i64.into()).is_err());
+        assert!(params.set("max", 9007199254740992i64.into()).is_ok());
+    }
+}
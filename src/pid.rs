@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+// everything a client can tune on a PID controller. The controller itself
+// is robot code, this crate only keeps track of the current values
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Gains {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+    // feedforward, multiplied by the setpoint
+    pub kf: f64,
+    // the integral term is clamped to +-integral_limit
+    pub integral_limit: Option<f64>,
+    // (min, max) the output is clamped to
+    pub output_limits: Option<(f64, f64)>,
+}
+
+impl Gains {
+    pub fn new(kp: f64, ki: f64, kd: f64) -> Self {
+        Self {
+            kp,
+            ki,
+            kd,
+            kf: 0.0,
+            integral_limit: None,
+            output_limits: None,
+        }
+    }
+    pub fn kf(mut self, kf: f64) -> Self {
+        self.kf = kf;
+        self
+    }
+    pub fn integral_limit(mut self, limit: f64) -> Self {
+        self.integral_limit = Some(limit);
+        self
+    }
+    pub fn output_limits(mut self, min: f64, max: f64) -> Self {
+        self.output_limits = Some((min, max));
+        self
+    }
+
+    // clamps value to output_limits
+    pub fn clamp_output(&self, value: f64) -> f64 {
+        match self.output_limits {
+            Some((min, max)) => value.clamp(min, max),
+            None => value,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if ![self.kp, self.ki, self.kd, self.kf]
+            .iter()
+            .all(|k| k.is_finite())
+        {
+            return Err(String::from("gains must be finite"));
+        }
+        if matches!(self.integral_limit, Some(limit) if limit.is_nan() || limit < 0.0) {
+            return Err(String::from("integral limit must be positive"));
+        }
+        if matches!(self.output_limits, Some((min, max)) if min.is_nan() || max.is_nan() || min > max)
+        {
+            return Err(String::from(
+                "output limits must be (min, max) with min <= max",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// the named controllers robot code has registered with
+// Mediator::register_pid, shared with the listener so that clients are
+// answered without waiting on the main loop
+#[derive(Debug, Default)]
+pub(crate) struct PidRegistry {
+    controllers: RwLock<BTreeMap<String, Gains>>,
+}
+
+impl PidRegistry {
+    // replaces the gains if name is already registered
+    pub(crate) fn register(&self, name: String, gains: Gains) {
+        self.write().insert(name, gains);
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<Gains, String> {
+        self.read()
+            .get(name)
+            .copied()
+            .ok_or_else(|| format!("no PID controller named {name}"))
+    }
+
+    // only registered controllers can be set, on error the gains are kept
+    pub(crate) fn set(&self, name: &str, gains: Gains) -> Result<Gains, String> {
+        gains.validate()?;
+        let mut controllers = self.write();
+        let current = controllers
+            .get_mut(name)
+            .ok_or_else(|| format!("no PID controller named {name}"))?;
+        *current = gains;
+        Ok(gains)
+    }
+
+    pub(crate) fn list(&self) -> Vec<(String, Gains)> {
+        self.read()
+            .iter()
+            .map(|(name, gains)| (name.clone(), *gains))
+            .collect()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<String, Gains>> {
+        self.controllers.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<String, Gains>> {
+        self.controllers.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_validates() {
+        let pids = PidRegistry::default();
+        let drive = Gains::new(1.0, 0.0, 0.1).output_limits(-1.0, 1.0);
+        pids.register(String::from("drive"), drive);
+
+        assert!(pids.set("lift", drive).is_err());
+        assert!(pids.set("drive", Gains::new(f64::NAN, 0.0, 0.0)).is_err());
+        assert!(pids.set("drive", drive.integral_limit(-1.0)).is_err());
+        assert!(pids.set("drive", drive.output_limits(1.0, -1.0)).is_err());
+        assert_eq!(pids.get("drive"), Ok(drive));
+
+        let tuned = drive.kf(0.5).integral_limit(0.2);
+        assert_eq!(pids.set("drive", tuned), Ok(tuned));
+        assert_eq!(pids.list(), [(String::from("drive"), tuned)]);
+        assert_eq!(tuned.clamp_output(3.0), 1.0);
+    }
+}
@@ -1,11 +1,7 @@
-use crate::packet::FromMediator;
-use crossbeam_channel::Sender;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
-pub static mut PLOTTER: Option<Sender<FromMediator>> = None;
-
 const BUFFER_SIZE: usize = 50;
 const BUFFER_TIMEOUT: Duration = Duration::from_millis(100);
 
@@ -13,6 +9,57 @@ const BUFFER_TIMEOUT: Duration = Duration::from_millis(100);
 // e.g. Names("encoder one", "target")
 pub(crate) type Names = (String, String);
 
+// a subplot's points are sent once it has size points buffered or timeout
+// has passed since they were last sent, whichever comes first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferSettings {
+    pub size: usize,
+    pub timeout: Duration,
+}
+
+impl Default for BufferSettings {
+    fn default() -> Self {
+        Self {
+            size: BUFFER_SIZE,
+            timeout: BUFFER_TIMEOUT,
+        }
+    }
+}
+
+// BufferSettings for every plot, set with LoggerConfig::plot_buffer and
+// overridden per plot with plot::configure
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PlotSettings {
+    default: BufferSettings,
+    plots: HashMap<String, BufferSettings>,
+}
+
+impl PlotSettings {
+    pub(crate) fn new(default: BufferSettings) -> Self {
+        Self {
+            default,
+            plots: HashMap::new(),
+        }
+    }
+    pub(crate) fn set(&mut self, plot: String, settings: BufferSettings) {
+        self.plots.insert(plot, settings);
+    }
+    fn get(&self, plot: &str) -> BufferSettings {
+        self.plots.get(plot).copied().unwrap_or(self.default)
+    }
+}
+
+// overrides the BufferSettings of every subplot of plot, e.g. a larger
+// timeout for a slowly changing value
+pub fn configure(plot: impl Into<String>, settings: BufferSettings) {
+    if let Some(sink) = crate::telemetry::sink() {
+        let pkt = crate::packet::FromMediator::PlotSettings((plot.into(), settings));
+        if let Err(e) = sink.send(pkt) {
+            log::warn!("Failed to send plot settings to listener thread: {e}");
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub(crate) struct PlotManager(HashMap<Names, SubPlot>);
 
@@ -27,11 +74,14 @@ impl PlotManager {
             }
         }
     }
-    pub(crate) fn buffers_to_send(&mut self) -> Vec<(Names, Buffer)> {
+    pub(crate) fn buffers_to_send(&mut self, settings: &PlotSettings) -> Vec<(Names, Buffer)> {
         let mut out = Vec::new();
         for plot in self.0.values_mut() {
-            if plot.point_buffer.len() >= BUFFER_SIZE
-                || (plot.last_update.elapsed() >= BUFFER_TIMEOUT && !plot.point_buffer.is_empty())
+            let settings = settings.get(&plot.names.0);
+            // a size of 0 sends every point as soon as it arrives
+            if !plot.point_buffer.is_empty()
+                && (plot.point_buffer.len() >= settings.size
+                    || plot.last_update.elapsed() >= settings.timeout)
             {
                 out.push((plot.names.clone(), plot.point_buffer.take()));
                 plot.last_update = Instant::now();
@@ -39,6 +89,17 @@ impl PlotManager {
         }
         out
     }
+    // every buffer that has points regardless of size or age
+    pub(crate) fn flush_all(&mut self) -> Vec<(Names, Buffer)> {
+        self.0
+            .values_mut()
+            .filter(|plot| !plot.point_buffer.is_empty())
+            .map(|plot| {
+                plot.last_update = Instant::now();
+                (plot.names.clone(), plot.point_buffer.take())
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug)]
@@ -117,24 +178,17 @@ macro_rules! plot {
         plot!($plt_name, $plt_name, $point)
     };
     ($plt_name:expr, $subplt_name:expr, $point:expr) => {
-        #[allow(unsafe_code)]
-        unsafe {
-            if let Some(sender) = &*std::ptr::addr_of!($crate::plot::PLOTTER) {
-                #[allow(unused_imports)]
-                use $crate::plot::{A, B, C};
-                match sender.try_send($crate::packet::FromMediator::Point((
-                    ($plt_name.into(), $subplt_name.into()),
-                    $point.into_plot_point(),
-                ))) {
-                    Err(e) if sender.len() <= 1 =>
-                    log::error!(
-                        "Failed to send plot data to listener thread with \"{e}\". This should never happen."
-                    ),
-                    _ => {},
-                };
-            }
+        if let Some(sink) = $crate::telemetry::sink() {
+            #[allow(unused_imports)]
+            use $crate::plot::{A, B, C};
+            let pkt = $crate::packet::FromMediator::Point((
+                ($plt_name.into(), $subplt_name.into()),
+                $point.into_plot_point(),
+            ));
+            // a full channel drops the point, as does a listener that has
+            // been shut down (see Mediator::shutdown)
+            let _ = sink.send(pkt);
         }
-
     };
 }
 
@@ -167,12 +221,60 @@ impl<T: Into<[f64; 3]>> C for T {}
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_settings() {
+        let mut settings = PlotSettings::new(BufferSettings {
+            size: 2,
+            timeout: Duration::from_secs(10),
+        });
+        settings.set(
+            String::from("slow"),
+            BufferSettings {
+                size: 100,
+                timeout: Duration::from_millis(10),
+            },
+        );
+        let names = |plot: &str| (String::from(plot), String::from(plot));
+
+        let mut manager = PlotManager::default();
+        manager.add_point((names("fast"), Point::Scalar((Instant::now(), 1.0))));
+        manager.add_point((names("slow"), Point::Scalar((Instant::now(), 1.0))));
+        assert!(manager.buffers_to_send(&settings).is_empty());
+
+        manager.add_point((names("fast"), Point::Scalar((Instant::now(), 2.0))));
+        let sent = manager.buffers_to_send(&settings);
+        assert!(matches!(sent.as_slice(), [(n, b)] if *n == names("fast") && b.len() == 2));
+
+        std::thread::sleep(Duration::from_millis(20));
+        let sent = manager.buffers_to_send(&settings);
+        assert!(matches!(sent.as_slice(), [(n, b)] if *n == names("slow") && b.len() == 1));
+    }
+
+    #[test]
+    fn unbuffered() {
+        let settings = PlotSettings::new(BufferSettings {
+            size: 0,
+            timeout: Duration::ZERO,
+        });
+        let names = (String::from("a"), String::from("a"));
+
+        let mut manager = PlotManager::default();
+        manager.add_point((names.clone(), Point::Scalar((Instant::now(), 1.0))));
+        let sent = manager.buffers_to_send(&settings);
+        assert!(matches!(sent.as_slice(), [(n, b)] if *n == names && b.len() == 1));
+        assert!(manager.buffers_to_send(&settings).is_empty());
+    }
+
     #[test]
     fn different_types() {
         plot!("", 3i32);
         plot!("", [3.2f64, 1.2]);
 
         struct Test([i32; 3]);
+        // plot! only needs Into, which is what this checks
+        #[allow(clippy::from_over_into)]
         impl Into<[f64; 3]> for Test {
             fn into(self) -> [f64; 3] {
                 [self.0[0] as f64, self.0[1] as f64, self.0[2] as f64]
@@ -0,0 +1,316 @@
+use crate::{
+    packet::{self, Capabilities, FrameDecoder, FromMediator, Hello, ToClient, PROTOCOL_VERSION},
+    plot::{PlotManager, PlotSettings},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+// how often the recording is flushed to disk, anything newer is lost if
+// the robot loses power
+const FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+const FILE_PREFIX: &str = "recording-";
+const FILE_EXTENSION: &str = "bin";
+
+// a single frame of a recording, the packet a client would have received
+// and when it was recorded
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct Recorded {
+    pub timestamp: SystemTime,
+    pub pkt: ToClient,
+}
+
+// appends logs, plot buffers and odometry to numbered files in dir using
+// the packet framing, starting a new file once the current one reaches
+// max_file_len and deleting the oldest beyond max_files. Every run starts
+// a new file so earlier runs are never overwritten
+pub(crate) struct Recorder {
+    dir: PathBuf,
+    max_file_len: u64,
+    max_files: usize,
+    index: u64,
+    file: BufWriter<File>,
+    // recorded bytes in the current file, not counting its header
+    file_len: u64,
+    last_flush: Instant,
+    // recorded with odometry, see LoggerConfig::first_robot
+    first_robot: bool,
+    // plot points are recorded as the same buffers clients receive
+    plot_manager: PlotManager,
+}
+
+impl Recorder {
+    pub(crate) fn new(
+        dir: impl Into<PathBuf>,
+        max_file_len: u64,
+        max_files: usize,
+        first_robot: bool,
+    ) -> Result<Self, packet::Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let index = indexed_recordings(&dir)?
+            .last()
+            .map_or(0, |(index, _)| index + 1);
+        let recorder = Self {
+            file: create(&dir, index)?,
+            dir,
+            max_file_len,
+            max_files,
+            index,
+            file_len: 0,
+            last_flush: Instant::now(),
+            first_robot,
+            plot_manager: PlotManager::default(),
+        };
+        recorder.remove_old()?;
+        Ok(recorder)
+    }
+
+    pub(crate) fn record(&mut self, pkt: &FromMediator) -> Result<(), packet::Error> {
+        match pkt {
+            FromMediator::Log(log) => self.write(ToClient::Log((**log).clone())),
+            FromMediator::ExtendedLog(log) => self.write(ToClient::ExtendedLog((**log).clone())),
+            FromMediator::Odometry((pos, heading)) => {
+                self.write(ToClient::Odometry((self.first_robot, *pos, *heading)))
+            }
+            FromMediator::Point(p) => {
+                self.plot_manager.add_point(p.clone());
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // writes any plot buffers that are ready and flushes to disk if due,
+    // called every iteration of the listener loop
+    pub(crate) fn tick(&mut self, plot_settings: &PlotSettings) -> Result<(), packet::Error> {
+        for buffer in self.plot_manager.buffers_to_send(plot_settings) {
+            self.write(ToClient::PointBuffer(buffer))?;
+        }
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.file.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn close(&mut self) -> Result<(), packet::Error> {
+        for buffer in self.plot_manager.flush_all() {
+            self.write(ToClient::PointBuffer(buffer))?;
+        }
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn write(&mut self, pkt: ToClient) -> Result<(), packet::Error> {
+        if self.file_len >= self.max_file_len {
+            self.rotate()?;
+        }
+        let mut frame = Vec::new();
+        let recorded = Recorded {
+            timestamp: SystemTime::now(),
+            pkt,
+        };
+        packet::send(&mut frame, &recorded)?;
+        self.file.write_all(&frame)?;
+        self.file_len += frame.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), packet::Error> {
+        self.file.flush()?;
+        self.index += 1;
+        self.file = create(&self.dir, self.index)?;
+        self.file_len = 0;
+        Ok(self.remove_old()?)
+    }
+
+    fn remove_old(&self) -> std::io::Result<()> {
+        let recordings = indexed_recordings(&self.dir)?;
+        let excess = recordings.len().saturating_sub(self.max_files.max(1));
+        for (_, path) in &recordings[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+// the recording files in dir, oldest first
+pub fn recordings(dir: impl AsRef<Path>) -> std::io::Result<Vec<PathBuf>> {
+    Ok(indexed_recordings(dir.as_ref())?
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect())
+}
+
+// reads every frame of a recording file. A partial frame at the end (e.g.
+// from losing power mid write) is ignored. Errors with
+// packet::Error::VersionMismatch if it was recorded with another version
+// of the protocol
+pub fn read_recording(path: impl AsRef<Path>) -> Result<Vec<Recorded>, packet::Error> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    let mut decoder = FrameDecoder::new(u32::MAX as usize);
+    decoder.push(&data);
+    let Some(header) = decoder.next_frame()? else {
+        return Ok(Vec::new());
+    };
+    let hello: Hello = bincode::deserialize(&header).map_err(packet::Error::Malformed)?;
+    if hello.version != PROTOCOL_VERSION {
+        return Err(packet::Error::VersionMismatch {
+            local: PROTOCOL_VERSION,
+            remote: hello.version,
+        });
+    }
+    let mut recorded = Vec::new();
+    while let Some(frame) = decoder.next_frame()? {
+        recorded.push(bincode::deserialize(&frame).map_err(packet::Error::Malformed)?);
+    }
+    Ok(recorded)
+}
+
+fn create(dir: &Path, index: u64) -> Result<BufWriter<File>, packet::Error> {
+    let path = dir.join(format!("{FILE_PREFIX}{index:06}.{FILE_EXTENSION}"));
+    let mut file = BufWriter::new(File::create(path)?);
+    write_header(&mut file)?;
+    Ok(file)
+}
+
+// every recording starts with a Hello so that one made with another
+// version of the protocol is refused rather than mis-decoded
+pub(crate) fn write_header(out: &mut impl Write) -> Result<(), packet::Error> {
+    packet::send(out, &Hello::new(Capabilities::ALL))
+}
+
+fn indexed_recordings(dir: &Path) -> std::io::Result<Vec<(u64, PathBuf)>> {
+    let mut recordings = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let index = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(FILE_PREFIX))
+            .and_then(|name| name.strip_suffix(FILE_EXTENSION))
+            .and_then(|name| name.strip_suffix('.'))
+            .and_then(|index| index.parse().ok());
+        if let Some(index) = index {
+            recordings.push((index, path));
+        }
+    }
+    recordings.sort();
+    Ok(recordings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{plot::Point, SimpleLog};
+
+    fn log(msg: &str) -> FromMediator {
+        FromMediator::Log(Box::new(SimpleLog {
+            level: log::Level::Info,
+            msg: String::from(msg),
+            target: String::from("test"),
+            timestamp: SystemTime::now(),
+            module_path: None,
+            file: None,
+            line: None,
+            fields: Default::default(),
+        }))
+    }
+
+    #[test]
+    fn records_and_rotates() {
+        let dir = std::env::temp_dir().join(format!("recorder-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        // a leftover recording from an earlier run, the oldest so removed first
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("recording-000007.bin")).unwrap();
+
+        // every frame is over 1 byte so each one starts a new file
+        let mut recorder = Recorder::new(&dir, 1, 3, true).unwrap();
+        recorder.record(&log("a")).unwrap();
+        recorder
+            .record(&FromMediator::Odometry(([1.0, 2.0], 3.0)))
+            .unwrap();
+        recorder
+            .record(&FromMediator::Point((
+                (String::from("plot"), String::from("plot")),
+                Point::Scalar((Instant::now(), 1.0)),
+            )))
+            .unwrap();
+        recorder.record(&FromMediator::Shutdown).unwrap();
+        recorder.close().unwrap();
+
+        let files = recordings(&dir).unwrap();
+        let names: Vec<_> = files.iter().map(|p| p.file_name().unwrap()).collect();
+        assert_eq!(
+            names,
+            [
+                "recording-000008.bin",
+                "recording-000009.bin",
+                "recording-000010.bin"
+            ]
+        );
+
+        let pkts: Vec<_> = files
+            .iter()
+            .flat_map(|file| read_recording(file).unwrap())
+            .map(|recorded| recorded.pkt)
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(
+            pkts.as_slice(),
+            [
+                ToClient::Log(log),
+                ToClient::Odometry((_, [1.0, 2.0], 3.0)),
+                ToClient::PointBuffer(_),
+            ] if log.msg == "a"
+        ));
+    }
+
+    #[test]
+    fn ignores_partial_frame() {
+        let path = std::env::temp_dir().join(format!("partial-test-{}.bin", std::process::id()));
+        let mut data = Vec::new();
+        let recorded = Recorded {
+            timestamp: SystemTime::now(),
+            pkt: ToClient::LogsDropped(1),
+        };
+        write_header(&mut data).unwrap();
+        packet::send(&mut data, &recorded).unwrap();
+        packet::send(&mut data, &recorded).unwrap();
+        data.truncate(data.len() - 1);
+        fs::write(&path, data).unwrap();
+
+        let res = read_recording(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(res.unwrap(), [recorded]);
+    }
+
+    #[test]
+    fn refuses_other_versions() {
+        let path = std::env::temp_dir().join(format!("version-test-{}.bin", std::process::id()));
+        let mut data = Vec::new();
+        let hello = Hello {
+            version: PROTOCOL_VERSION - 1,
+            capabilities: Capabilities::ALL,
+        };
+        packet::send(&mut data, &hello).unwrap();
+        fs::write(&path, data).unwrap();
+
+        let res = read_recording(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            res,
+            Err(packet::Error::VersionMismatch { remote, .. }) if remote == PROTOCOL_VERSION - 1
+        ));
+    }
+}
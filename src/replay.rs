@@ -0,0 +1,258 @@
+use crate::{
+    packet::{self, Capabilities, FrameDecoder, Heartbeat, PongSource, ToClient, ToRobot},
+    recorder::{self, Recorded},
+};
+use std::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// how often the client is checked for pings while waiting to send the
+// next packet
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("replay speed must be positive, got {0}")]
+    InvalidSpeed(f64),
+}
+
+// serves a recording (see recorder.rs) to clients as if it were a robot,
+// e.g. to develop a GUI against match data without a robot. Every client
+// gets the whole recording from the start with its original timing
+// divided by speed
+pub struct Replayer {
+    recording: Vec<Recorded>,
+    speed: f64,
+}
+
+impl Replayer {
+    pub fn new(recording: Vec<Recorded>) -> Self {
+        Self {
+            recording,
+            speed: 1.0,
+        }
+    }
+
+    // the files are played one after the other, see recorder::recordings
+    pub fn from_files<P: AsRef<Path>>(
+        paths: impl IntoIterator<Item = P>,
+    ) -> Result<Self, packet::Error> {
+        let mut recording = Vec::new();
+        for path in paths {
+            recording.extend(recorder::read_recording(path)?);
+        }
+        Ok(Self::new(recording))
+    }
+
+    // 2.0 plays twice as fast, f64::INFINITY sends everything at once.
+    // Errors if speed is zero, negative or NaN
+    pub fn speed(mut self, speed: f64) -> Result<Self, Error> {
+        if speed.is_nan() || speed <= 0.0 {
+            return Err(Error::InvalidSpeed(speed));
+        }
+        self.speed = speed;
+        Ok(self)
+    }
+
+    // binds to addr and serves every client that connects, never returns
+    // unless accepting fails
+    pub fn serve<A: ToSocketAddrs>(self, addr: A) -> std::io::Result<()> {
+        self.serve_on(TcpListener::bind(addr)?)
+    }
+
+    pub fn serve_on(self, tcp: TcpListener) -> std::io::Result<()> {
+        let replayer = Arc::new(self);
+        loop {
+            let (stream, addr) = tcp.accept()?;
+            let replayer = replayer.clone();
+            std::thread::spawn(move || {
+                log::info!("Replaying to client {addr}.");
+                if let Err(e) = replayer.replay(stream) {
+                    log::warn!("Client {addr} disconnected: {e}");
+                }
+            });
+        }
+    }
+
+    // replays the recording to a single client then hangs up
+    pub fn replay(&self, mut stream: TcpStream) -> Result<(), packet::Error> {
+        stream.set_nodelay(true)?;
+        let capabilities = packet::handshake(&mut stream, Capabilities::ALL)?;
+        let mut decoder = FrameDecoder::default();
+
+        let Some(first) = self.recording.first() else {
+            return Ok(());
+        };
+        let start = Instant::now();
+        // a long gap in the recording mustn't time the client out
+        let mut last_heartbeat = start;
+        let heartbeat = Heartbeat::default();
+        for recorded in &self.recording {
+            let offset = scale(
+                recorded
+                    .timestamp
+                    .duration_since(first.timestamp)
+                    .unwrap_or_default(),
+                self.speed,
+            );
+            loop {
+                answer_pings(&mut stream, &mut decoder)?;
+                if last_heartbeat.elapsed() >= heartbeat.interval {
+                    packet::send(&mut stream, &ToClient::Heartbeat)?;
+                    last_heartbeat = Instant::now();
+                }
+                let remaining = offset.saturating_sub(start.elapsed());
+                if remaining.is_zero() {
+                    break;
+                }
+                std::thread::sleep(remaining.min(POLL_INTERVAL));
+            }
+            if let Some(pkt) = filter(capabilities, &recorded.pkt) {
+                packet::send(&mut stream, &pkt)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// ToRobot::RequestLogs is ignored as the whole recording is sent anyway
+// offset played at speed, a tiny speed saturates rather than overflowing
+fn scale(offset: Duration, speed: f64) -> Duration {
+    Duration::try_from_secs_f64(offset.as_secs_f64() / speed).unwrap_or(Duration::MAX)
+}
+
+fn answer_pings(stream: &mut TcpStream, decoder: &mut FrameDecoder) -> Result<(), packet::Error> {
+    packet::recieve_multiple(stream, decoder, &mut |stream, pkt| match pkt {
+        // there is no main loop, so no PongSource::Mediator
+        ToRobot::Ping(seq) => packet::send(
+            stream,
+            &ToClient::Pong {
+                seq,
+                source: PongSource::Listener,
+            },
+        ),
+        // there is no robot code to answer these
+        ToRobot::GetPid(name) | ToRobot::SetPid((name, _)) => packet::send(
+            stream,
+            &ToClient::Pid {
+                name,
+                result: Err(String::from("PIDs aren't supported when replaying")),
+            },
+        ),
+        ToRobot::ListPids => packet::send(stream, &ToClient::Pids(Vec::new())),
+        ToRobot::GetParam(name) | ToRobot::SetParam((name, _)) => packet::send(
+            stream,
+            &ToClient::Param {
+                name,
+                result: Err(String::from("parameters aren't supported when replaying")),
+            },
+        ),
+        ToRobot::ListParams => packet::send(stream, &ToClient::Params(Vec::new())),
+        ToRobot::Request { id, .. } => packet::send(
+            stream,
+            &ToClient::Response {
+                id,
+                result: Err(String::from("requests aren't supported when replaying")),
+            },
+        ),
+        _ => Ok(()),
+    })
+}
+
+// mirrors the capability checks the listener does before sending
+fn filter(capabilities: Capabilities, pkt: &ToClient) -> Option<ToClient> {
+    let required = match pkt {
+        ToClient::Log(_) | ToClient::ExtendedLog(_) | ToClient::LogsDropped(_) => {
+            Capabilities::LOGS
+        }
+        ToClient::Pong { .. }
+        | ToClient::LogFilter(_)
+        | ToClient::Dropped(_)
+        | ToClient::Response { .. }
+        | ToClient::Heartbeat => return Some(pkt.clone()),
+        ToClient::Pid { .. } | ToClient::Pids(_) => Capabilities::PID,
+        ToClient::Params(_) | ToClient::Param { .. } | ToClient::ParamChanged { .. } => {
+            Capabilities::PARAMS
+        }
+        ToClient::Path(_) => Capabilities::PATHS,
+        ToClient::PointBuffer(_) => Capabilities::PLOTS,
+        ToClient::Odometry(_) => Capabilities::ODOMETRY,
+    };
+    if !capabilities.contains(required) {
+        return None;
+    }
+    match pkt {
+        ToClient::ExtendedLog(log) if !capabilities.contains(Capabilities::EXTENDED_LOGS) => {
+            Some(ToClient::Log(log.log.clone()))
+        }
+        pkt => Some(pkt.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use std::time::SystemTime;
+
+    #[test]
+    fn replays_with_timing() {
+        let start = SystemTime::now();
+        let recording: Vec<_> = (0..3)
+            .map(|i| Recorded {
+                timestamp: start + Duration::from_millis(100) * i,
+                pkt: ToClient::Odometry((true, [i as f64, 0.0], 0.0)),
+            })
+            .collect();
+        // 200ms of recording played back in 100ms
+        let replayer = Replayer::new(recording.clone()).speed(2.0).unwrap();
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let thread = std::thread::spawn(move || {
+            let stream = tcp.accept().unwrap().0;
+            replayer.replay(stream).unwrap();
+        });
+
+        let mut client = Client::new(addr).unwrap();
+        let connected = Instant::now();
+        client.ping().unwrap();
+        let mut pkts = Vec::new();
+        while pkts.len() < 4 && connected.elapsed() < Duration::from_secs(2) {
+            pkts.extend(client.receive_data().unwrap());
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        let elapsed = connected.elapsed();
+        thread.join().unwrap();
+
+        assert!(elapsed >= Duration::from_millis(90), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(1), "{elapsed:?}");
+        let expected: Vec<_> = recording.into_iter().map(|r| r.pkt).collect();
+        let odometry: Vec<_> = pkts
+            .iter()
+            .filter(|p| !matches!(p, ToClient::Pong { .. }))
+            .cloned()
+            .collect();
+        assert_eq!(odometry, expected);
+        assert!(client.rtt(PongSource::Listener).is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_speed() {
+        for speed in [0.0, -1.0, f64::NAN] {
+            assert!(Replayer::new(Vec::new()).speed(speed).is_err());
+        }
+        assert!(Replayer::new(Vec::new()).speed(f64::INFINITY).is_ok());
+    }
+
+    #[test]
+    fn scales_offsets() {
+        let offset = Duration::from_millis(100);
+        assert_eq!(scale(offset, 2.0), Duration::from_millis(50));
+        assert_eq!(scale(offset, f64::INFINITY), Duration::ZERO);
+        assert_eq!(scale(offset, 1e-30), Duration::MAX);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::packet;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+// a typed request/response pair, e.g.
+//
+// struct GetPid;
+// impl Rpc for GetPid {
+//     const METHOD: &'static str = "get_pid";
+//     type Request = String;
+//     type Response = (f64, f64, f64);
+// }
+//
+// clients call it with Client::call or Client::call_with and the robot
+// answers the ToMediator::Request it receives with Mediator::respond
+pub trait Rpc: 'static {
+    const METHOD: &'static str;
+    type Request: Serialize + DeserializeOwned;
+    type Response: Serialize + DeserializeOwned;
+}
+
+// identifies a connected client for the lifetime of the listener
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ClientId(pub(crate) u64);
+
+// a request from a client, every request should be answered with
+// Mediator::respond or Mediator::respond_err
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub client: ClientId,
+    pub(crate) id: u64,
+    pub method: String,
+    pub(crate) payload: Vec<u8>,
+}
+
+impl Request {
+    // None if this request is for a different method
+    pub fn parse<R: Rpc>(&self) -> Option<Result<R::Request, bincode::Error>> {
+        (self.method == R::METHOD).then(|| bincode::deserialize(&self.payload))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("packet error:\n{0}")]
+    Packet(#[from] packet::Error),
+    #[error("timed out waiting for a response")]
+    Timeout,
+    #[error("robot responded with an error: {0}")]
+    Remote(String),
+    #[error("failed to decode response:\n{0}")]
+    Decode(bincode::Error),
+}
+
+pub(crate) fn decode<R: Rpc>(result: Result<Vec<u8>, String>) -> Result<R::Response, Error> {
+    bincode::deserialize(&result.map_err(Error::Remote)?).map_err(Error::Decode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::Client, packet::ToMediator, tests::spawn_listener, LoggerConfig};
+    use std::{
+        net::Ipv4Addr,
+        sync::mpsc,
+        time::{Duration, Instant},
+    };
+
+    struct Add;
+    impl Rpc for Add {
+        const METHOD: &'static str = "add";
+        type Request = (i32, i32);
+        type Response = i32;
+    }
+
+    struct Unanswered;
+    impl Rpc for Unanswered {
+        const METHOD: &'static str = "unanswered";
+        type Request = ();
+        type Response = ();
+    }
+
+    struct Unknown;
+    impl Rpc for Unknown {
+        const METHOD: &'static str = "unknown";
+        type Request = ();
+        type Response = ();
+    }
+
+    #[test]
+    fn calls() {
+        let config = LoggerConfig::default()
+            .bind_address(Ipv4Addr::LOCALHOST)
+            .port(0);
+        let (mut mediator, _) = spawn_listener(config);
+        let addr = mediator.local_addr();
+
+        let client = std::thread::spawn(move || {
+            let mut client = Client::new(addr).unwrap();
+            let timeout = Duration::from_secs(1);
+            assert_eq!(client.call::<Add>(&(2, 3), timeout).unwrap(), 5);
+            assert!(matches!(
+                client.call::<Unknown>(&(), timeout),
+                Err(Error::Remote(_))
+            ));
+            assert!(matches!(
+                client.call::<Unanswered>(&(), Duration::from_millis(50)),
+                Err(Error::Timeout)
+            ));
+
+            let (tx, rx) = mpsc::channel();
+            client
+                .call_with::<Add>(&(4, 5), timeout, move |res| tx.send(res.unwrap()).unwrap())
+                .unwrap();
+            let start = Instant::now();
+            while rx.try_recv().is_err() {
+                assert!(start.elapsed() < timeout);
+                client.receive_data().unwrap();
+                std::thread::sleep(Duration::from_millis(2));
+            }
+        });
+
+        while !client.is_finished() {
+            for event in mediator.poll_events().unwrap() {
+                let ToMediator::Request(req) = event else {
+                    continue;
+                };
+                if let Some(args) = req.parse::<Add>() {
+                    let (a, b) = args.unwrap();
+                    mediator.respond::<Add>(&req, &(a + b)).unwrap();
+                } else if req.parse::<Unanswered>().is_none() {
+                    mediator.respond_err(&req, "unknown method").unwrap();
+                }
+            }
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        client.join().unwrap();
+
+        mediator.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,357 @@
+use crate::packet::FromMediator;
+use crossbeam_channel::{Receiver, SendTimeoutError, Sender};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+pub use crossbeam_channel::TrySendError;
+
+// where plot!, odom and any other telemetry are sent. Logger::init installs
+// one pointing at its listener but tools and tests can install their own
+// without installing the logger
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+thread_local! {
+    // set on listener threads, whose own logs mustn't wait on themselves
+    static ON_LISTENER: Cell<bool> = const { Cell::new(false) };
+}
+
+pub(crate) fn mark_listener_thread() {
+    ON_LISTENER.set(true);
+}
+
+// what to do with logs, plot points and odometry when the channel to the
+// listener is full
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverflowPolicy {
+    // discard whatever is being sent
+    #[default]
+    DropNewest,
+    // discard the oldest queued log, point or odometry sample to make room
+    DropOldest,
+    // wait up to the timeout for room then discard what is being sent.
+    // Note this can stall the control loop. Logs from the listener thread
+    // itself are dropped straight away instead
+    Block(Duration),
+}
+
+// how many of each kind of telemetry have been discarded
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Dropped {
+    pub logs: u64,
+    pub points: u64,
+    pub odometry: u64,
+    // packets from clients and connection events the main loop never saw
+    // because it wasn't polling the Mediator often enough
+    pub events: u64,
+}
+
+impl Dropped {
+    pub fn total(&self) -> u64 {
+        self.logs + self.points + self.odometry + self.events
+    }
+    pub(crate) fn since(&self, earlier: &Self) -> Self {
+        Self {
+            logs: self.logs - earlier.logs,
+            points: self.points - earlier.points,
+            odometry: self.odometry - earlier.odometry,
+            events: self.events - earlier.events,
+        }
+    }
+}
+
+// shared by every Sink of a Logger so drops are counted in one place
+#[derive(Debug, Default)]
+pub(crate) struct DropCounters {
+    logs: AtomicU64,
+    points: AtomicU64,
+    odometry: AtomicU64,
+    events: AtomicU64,
+}
+
+impl DropCounters {
+    pub(crate) fn get(&self) -> Dropped {
+        Dropped {
+            logs: self.logs.load(Ordering::Relaxed),
+            points: self.points.load(Ordering::Relaxed),
+            odometry: self.odometry.load(Ordering::Relaxed),
+            events: self.events.load(Ordering::Relaxed),
+        }
+    }
+    pub(crate) fn count_event(&self) {
+        self.events.fetch_add(1, Ordering::Relaxed);
+    }
+    fn count(&self, pkt: &FromMediator) {
+        let counter = match pkt {
+            FromMediator::Log(_) | FromMediator::ExtendedLog(_) => &self.logs,
+            FromMediator::Point(_) => &self.points,
+            FromMediator::Odometry(_) => &self.odometry,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn is_telemetry(pkt: &FromMediator) -> bool {
+    matches!(
+        pkt,
+        FromMediator::Log(_)
+            | FromMediator::ExtendedLog(_)
+            | FromMediator::Point(_)
+            | FromMediator::Odometry(_)
+    )
+}
+
+// the receiving end of a Logger's telemetry channel, kept for
+// OverflowPolicy::DropOldest until the listener exits. Closing it then
+// means sinks see a disconnected channel rather than one that stays full
+#[derive(Debug, Default)]
+pub(crate) struct SinkReceiver(Mutex<Option<Receiver<FromMediator>>>);
+
+impl SinkReceiver {
+    pub(crate) fn new(receiver: Receiver<FromMediator>) -> Self {
+        Self(Mutex::new(Some(receiver)))
+    }
+
+    pub(crate) fn close(&self) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+
+    fn try_recv(&self) -> Option<FromMediator> {
+        let receiver = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        receiver.as_ref()?.try_recv().ok()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sink {
+    // logs, plot points and odometry, the only packets ever discarded to
+    // make room so that DropOldest never reorders or loses anything else
+    telemetry: Sender<FromMediator>,
+    // anything else, e.g. plot settings
+    other: Sender<FromMediator>,
+    // only kept for OverflowPolicy::DropOldest
+    receiver: Option<Arc<SinkReceiver>>,
+    policy: OverflowPolicy,
+    dropped: Arc<DropCounters>,
+}
+
+impl Sink {
+    // a sink that drops the newest telemetry when full
+    pub fn new(sender: Sender<FromMediator>) -> Self {
+        Self {
+            telemetry: sender.clone(),
+            other: sender,
+            receiver: None,
+            policy: OverflowPolicy::DropNewest,
+            dropped: Arc::default(),
+        }
+    }
+    pub(crate) fn with_policy(
+        telemetry: Sender<FromMediator>,
+        other: Sender<FromMediator>,
+        receiver: Arc<SinkReceiver>,
+        policy: OverflowPolicy,
+        dropped: Arc<DropCounters>,
+    ) -> Self {
+        Self {
+            telemetry,
+            other,
+            receiver: (policy == OverflowPolicy::DropOldest).then_some(receiver),
+            policy,
+            dropped,
+        }
+    }
+    // Err(Full) means pkt was discarded, every discarded packet is counted
+    // in dropped()
+    pub fn send(&self, pkt: FromMediator) -> Result<(), TrySendError<FromMediator>> {
+        if !is_telemetry(&pkt) {
+            return self.other.try_send(pkt);
+        }
+        let res = match self.policy {
+            OverflowPolicy::DropNewest => self.telemetry.try_send(pkt),
+            OverflowPolicy::DropOldest => self.send_drop_oldest(pkt),
+            OverflowPolicy::Block(_) if ON_LISTENER.get() => self.telemetry.try_send(pkt),
+            OverflowPolicy::Block(timeout) => {
+                self.telemetry
+                    .send_timeout(pkt, timeout)
+                    .map_err(|e| match e {
+                        SendTimeoutError::Timeout(pkt) => TrySendError::Full(pkt),
+                        SendTimeoutError::Disconnected(pkt) => TrySendError::Disconnected(pkt),
+                    })
+            }
+        };
+        if let Err(TrySendError::Full(pkt)) = &res {
+            self.dropped.count(pkt);
+        }
+        res
+    }
+    pub fn dropped(&self) -> Dropped {
+        self.dropped.get()
+    }
+
+    fn send_drop_oldest(&self, pkt: FromMediator) -> Result<(), TrySendError<FromMediator>> {
+        let pkt = match self.telemetry.try_send(pkt) {
+            Err(TrySendError::Full(pkt)) => pkt,
+            res => return res,
+        };
+        if let Some(oldest) = self.receiver.as_ref().and_then(|r| r.try_recv()) {
+            self.dropped.count(&oldest);
+        }
+        // can still be full if another thread got there first
+        self.telemetry.try_send(pkt)
+    }
+}
+
+// installs the global sink, fails returning the sink if one is already installed
+pub fn install(sink: Sink) -> Result<(), Sink> {
+    SINK.set(sink)
+}
+
+pub fn sink() -> Option<&'static Sink> {
+    SINK.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::bounded;
+
+    #[test]
+    fn sink_drops_when_full() {
+        let (tx, rx) = bounded(1);
+        let sink = Sink::new(tx);
+        sink.send(FromMediator::Odometry(([1.0, 2.0], 3.0)))
+            .unwrap();
+        assert!(matches!(
+            sink.send(FromMediator::Odometry(([4.0, 5.0], 6.0))),
+            Err(TrySendError::Full(_))
+        ));
+        assert_eq!(
+            sink.dropped(),
+            Dropped {
+                odometry: 1,
+                ..Default::default()
+            }
+        );
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(FromMediator::Odometry(([1.0, 2.0], 3.0)))
+        ));
+
+        drop(rx);
+        assert!(matches!(
+            sink.send(FromMediator::Shutdown),
+            Err(TrySendError::Disconnected(_))
+        ));
+    }
+
+    fn odometry(x: f64) -> FromMediator {
+        FromMediator::Odometry(([x, 0.0], 0.0))
+    }
+
+    fn drop_oldest_sink(
+        capacity: usize,
+    ) -> (
+        Sink,
+        Receiver<FromMediator>,
+        Receiver<FromMediator>,
+        Arc<SinkReceiver>,
+    ) {
+        let (tx, rx) = bounded(capacity);
+        let (other_tx, other_rx) = bounded(capacity);
+        let receiver = Arc::new(SinkReceiver::new(rx.clone()));
+        let sink = Sink::with_policy(
+            tx,
+            other_tx,
+            receiver.clone(),
+            OverflowPolicy::DropOldest,
+            Arc::default(),
+        );
+        (sink, rx, other_rx, receiver)
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (sink, rx, other_rx, _) = drop_oldest_sink(2);
+        for i in 0..3 {
+            sink.send(odometry(i as f64)).unwrap();
+        }
+        assert_eq!(sink.dropped().odometry, 1);
+
+        // other packets never take up room or get discarded
+        sink.send(FromMediator::Path(Vec::new())).unwrap();
+        sink.send(odometry(3.0)).unwrap();
+        assert_eq!(sink.dropped().odometry, 2);
+        assert!(matches!(other_rx.try_recv(), Ok(FromMediator::Path(_))));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(FromMediator::Odometry(([2.0, 0.0], _)))
+        ));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(FromMediator::Odometry(([3.0, 0.0], _)))
+        ));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn closed_receiver_disconnects() {
+        let (sink, rx, _, receiver) = drop_oldest_sink(1);
+        sink.send(odometry(0.0)).unwrap();
+        receiver.close();
+        drop(rx);
+        assert!(matches!(
+            sink.send(odometry(1.0)),
+            Err(TrySendError::Disconnected(_))
+        ));
+    }
+
+    fn blocking_sink() -> (Sink, Receiver<FromMediator>) {
+        let (tx, rx) = bounded(1);
+        let sink = Sink::with_policy(
+            tx.clone(),
+            tx,
+            Arc::default(),
+            OverflowPolicy::Block(Duration::from_millis(20)),
+            Arc::default(),
+        );
+        (sink, rx)
+    }
+
+    #[test]
+    fn block_times_out() {
+        let (sink, _rx) = blocking_sink();
+        sink.send(odometry(0.0)).unwrap();
+        let start = std::time::Instant::now();
+        assert!(sink
+            .send(FromMediator::Point((
+                (String::from("plot"), String::from("plot")),
+                crate::plot::Point::Scalar((start, 1.0)),
+            )))
+            .is_err());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(sink.dropped().points, 1);
+    }
+
+    #[test]
+    fn listener_never_blocks() {
+        let (sink, _rx) = blocking_sink();
+        std::thread::spawn(move || {
+            mark_listener_thread();
+            sink.send(odometry(0.0)).unwrap();
+            let start = std::time::Instant::now();
+            assert!(sink.send(odometry(1.0)).is_err());
+            assert!(start.elapsed() < Duration::from_millis(20));
+            assert_eq!(sink.dropped().odometry, 1);
+        })
+        .join()
+        .unwrap();
+    }
+}